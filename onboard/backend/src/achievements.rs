@@ -0,0 +1,175 @@
+use crate::persistence::{self, names};
+use crate::stats::unix_secs;
+use anyhow::Error;
+use devcade_onboard_types::error::{ErrorKind, ResponseError};
+use devcade_onboard_types::{Achievement, AchievementStatus, Event, Value};
+use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
+
+/**
+ * Directory inside a game's save directory that its achievements are kept in. Like player save
+ * data, it is hidden from the game's shared groups.
+ */
+pub const ACHIEVEMENT_DIR: &str = ".achievements";
+
+/**
+ * Group holding a game's achievement definitions, by achievement ID
+ */
+fn definitions_group(game_id: &str) -> String {
+    format!("{game_id}/{ACHIEVEMENT_DIR}/definitions")
+}
+
+/**
+ * Group holding when each of a game's achievements was first unlocked by anyone, by achievement ID
+ */
+fn unlocked_group(game_id: &str) -> String {
+    format!("{game_id}/{ACHIEVEMENT_DIR}/unlocked")
+}
+
+/**
+ * Directory holding one group per player, with when they unlocked each achievement
+ */
+fn players_dir(game_id: &str) -> String {
+    format!("{game_id}/{ACHIEVEMENT_DIR}/players")
+}
+
+/**
+ * Load every achievement a game has defined, by ID
+ */
+async fn definitions(game_id: &str) -> Result<HashMap<String, Achievement>, Error> {
+    let group = definitions_group(game_id);
+    let mut achievements = HashMap::new();
+    for id in persistence::list_keys(&group).await? {
+        let achievement = serde_json::from_value(persistence::load(&group, &id).await?)?;
+        achievements.insert(id, achievement);
+    }
+    Ok(achievements)
+}
+
+/**
+ * Replace the achievements a game has defined. Unlocks of achievements that are no longer defined
+ * are kept, in case the game defines them again.
+ *
+ * # Errors
+ * This function will return an error if an achievement has an empty or invalid ID, if two
+ * achievements share an ID, or if the definitions can't be saved.
+ */
+pub async fn define(game_id: &str, achievements: Vec<Achievement>) -> Result<(), Error> {
+    names::check_game_id(game_id)?;
+    let mut ids = HashSet::new();
+    for achievement in &achievements {
+        if achievement.id.is_empty() {
+            return Err(ResponseError::new(
+                ErrorKind::InvalidRequest,
+                "Achievement IDs can't be empty",
+            )
+            .into());
+        }
+        names::check_key(&achievement.id)?;
+        if !ids.insert(achievement.id.as_str()) {
+            return Err(ResponseError::new(
+                ErrorKind::InvalidRequest,
+                format!("Achievement {} is defined more than once", achievement.id),
+            )
+            .into());
+        }
+    }
+
+    let group = definitions_group(game_id);
+    for id in persistence::list_keys(&group).await? {
+        if !ids.contains(id.as_str()) {
+            persistence::delete(&group, &id).await?;
+        }
+    }
+    for achievement in achievements {
+        let id = achievement.id.clone();
+        persistence::save(&group, &id, serde_json::to_value(achievement)?).await?;
+    }
+    Ok(())
+}
+
+/**
+ * Unlock one of a game's achievements, for a single player if `player` is given. Anything unlocked
+ * for a player is also unlocked for the game. Subscribers are told about every new unlock.
+ * Returns whether the achievement was newly unlocked (for the player, if given).
+ *
+ * # Errors
+ * This function will return an error if the game hasn't defined the achievement, or if the unlock
+ * can't be saved.
+ */
+pub async fn unlock(game_id: &str, id: &str, player: Option<String>) -> Result<bool, Error> {
+    names::check_game_id(game_id)?;
+    let Some(achievement) = definitions(game_id).await?.remove(id) else {
+        return Err(ResponseError::new(
+            ErrorKind::NotFound,
+            format!("Game {game_id} has no achievement {id}"),
+        )
+        .into());
+    };
+
+    let now = Value::from(unix_secs(SystemTime::now()));
+    let unlocked_for_game =
+        persistence::compare_and_swap(&unlocked_group(game_id), id, None, now.clone())
+            .await?
+            .swapped;
+    let unlocked = match &player {
+        Some(player) => {
+            let group = persistence::game_group(&players_dir(game_id), player)?;
+            persistence::compare_and_swap(&group, id, None, now)
+                .await?
+                .swapped
+        }
+        None => unlocked_for_game,
+    };
+
+    if unlocked {
+        log::info!("Achievement {id} unlocked in {game_id}");
+        crate::events::publish(Event::AchievementUnlocked {
+            game_id: game_id.to_string(),
+            achievement,
+            player,
+        });
+    }
+    Ok(unlocked)
+}
+
+/**
+ * Get every achievement a game has defined with who has unlocked it, sorted by ID
+ *
+ * # Errors
+ * This function will return an error if the game ID isn't valid, or if the achievements can't be
+ * loaded.
+ */
+pub async fn achievements(game_id: &str) -> Result<Vec<AchievementStatus>, Error> {
+    names::check_game_id(game_id)?;
+
+    let mut players: HashMap<String, u64> = HashMap::new();
+    for player in persistence::list_groups(&players_dir(game_id)).await? {
+        let group = format!("{}/{player}", players_dir(game_id));
+        for id in persistence::list_keys(&group).await? {
+            *players.entry(id).or_default() += 1;
+        }
+    }
+
+    let unlocked = unlocked_group(game_id);
+    let mut achievements = Vec::new();
+    for (id, achievement) in definitions(game_id).await? {
+        let unlocked_at = match persistence::load(&unlocked, &id).await {
+            Ok(value) => value.as_u64(),
+            Err(err) => match err.downcast_ref::<ResponseError>() {
+                Some(ResponseError {
+                    kind: ErrorKind::KeyNotFound,
+                    ..
+                }) => None,
+                _ => return Err(err),
+            },
+        };
+        achievements.push(AchievementStatus {
+            players: players.get(&id).copied().unwrap_or(0),
+            achievement,
+            unlocked_at,
+        });
+    }
+    achievements.sort_by(|a, b| a.achievement.id.cmp(&b.achievement.id));
+    Ok(achievements)
+}
@@ -1,74 +1,761 @@
 use crate::env::{api_url, devcade_path};
 use crate::nfc::NFC_CLIENT;
-use anyhow::{anyhow, Error};
+use crate::persistence;
+use crate::supervisor;
+use crate::watchdog::Watchdog;
+use anyhow::Error;
 use devcade_onboard_types::{
+    error::{ErrorKind, ResponseError},
     schema::{DevcadeGame, MinimalGame, Tag, User},
-    Map, Player, Value,
+    Event, GameFetchError, Map, PartialGameList, Player, Progress, Value,
 };
+use futures_util::StreamExt;
 use log::{log, Level};
 
 use lazy_static::lazy_static;
 use libflatpak::{gio, prelude::*, Installation, Transaction};
-use std::cell::Cell;
-use std::collections::{HashMap, HashSet};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
 use std::path::{Path, PathBuf};
 use std::process::Stdio;
+use std::rc::Rc;
 use std::sync::Mutex;
 use std::time::Duration;
 use tokio::fs;
+use tokio::io::AsyncWriteExt;
 use tokio::process::Command;
-use tokio::sync::oneshot;
+use tokio::sync::{mpsc, oneshot};
+
+/**
+ * Channel used to report the progress of a long running request back to the client that made it
+ */
+pub type ProgressSender = mpsc::UnboundedSender<Progress>;
+
+/**
+ * Number of games fetched at the same time when they aren't in the game list
+ */
+const MAX_CONCURRENT_FETCHES: usize = 4;
 
 lazy_static! {
     static ref CURRENT_GAME: Mutex<Cell<DevcadeGame>> =
         Mutex::new(Cell::new(DevcadeGame::default()));
-    // basically just checks if a user 'devcade' exists. If so, assumes that this is running on the
-    // machine, and saves to the homedir. Otherwise, saves to the cwd.
-    static ref ON_MACHINE: bool = Path::new("/home/devcade").exists();
-    static ref DB: tokio::sync::Mutex<HashMap<String, HashMap<String, String>>> = tokio::sync::Mutex::new(HashMap::new());
-    static ref DB_MODIFIED: tokio::sync::Mutex<HashSet<String>> = tokio::sync::Mutex::new(HashSet::new());
+}
+
+/**
+ * Something fetched from the API. If the API couldn't give a current copy, a cached one is used
+ * instead and `stale_since` is when the API last confirmed it, in seconds since the unix epoch.
+ */
+#[derive(Debug, Clone)]
+pub struct Fetched<T> {
+    pub value: T,
+    pub stale_since: Option<u64>,
+}
+
+impl<T> Fetched<T> {
+    /**
+     * Build something else from the fetched value, which is just as stale
+     */
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Fetched<U> {
+        Fetched {
+            value: f(self.value),
+            stale_since: self.stale_since,
+        }
+    }
+}
+
+/**
+ * When the oldest of several fetched values was confirmed by the API, if any of them are stale
+ */
+fn oldest(stale_since: impl IntoIterator<Item = Option<u64>>) -> Option<u64> {
+    stale_since.into_iter().flatten().min()
+}
+
+/**
+ * Build an error that is reported to clients with the given kind.
+ */
+fn error(kind: ErrorKind, message: impl Into<String>) -> Error {
+    ResponseError::new(kind, message).into()
 }
 
 /**
  * Internal module for network requests and JSON serialization
  */
 mod network {
+    use super::Fetched;
+    use crate::env::{api_cache_dir, api_cache_ttl, api_connect_timeout, api_read_timeout};
+    use crate::stats::unix_secs;
     use anyhow::Error;
+    use devcade_onboard_types::error::{ErrorKind, ResponseError};
+    use devcade_onboard_types::Event;
     use lazy_static::lazy_static;
     use log::{log, Level};
-    use serde::Deserialize;
+    use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+    use reqwest::{RequestBuilder, StatusCode};
+    use serde::{Deserialize, Serialize};
+    use std::collections::HashMap;
+    use std::future::Future;
     use std::ops::Deref;
+    use std::path::{Path, PathBuf};
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant, SystemTime};
+
+    /**
+     * Number of times a request is sent before giving up, if the API can't be reached or answers
+     * with a server error
+     */
+    const MAX_ATTEMPTS: u32 = 3;
+
+    /**
+     * How long to wait before sending a request again. This doubles after every attempt.
+     */
+    const INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+
+    /**
+     * Number of requests in a row that have to fail before the API is considered offline
+     */
+    const FAILURES_BEFORE_OFFLINE: u32 = 5;
+
+    /**
+     * How long requests aren't sent once the API is considered offline, before one is let through
+     * to check whether it is back
+     */
+    const OFFLINE_COOLDOWN: Duration = Duration::from_secs(30);
 
     // Construct a static client to be used for all requests. Prevents opening a new connection for
     // every request.
     lazy_static! {
-        static ref CLIENT: reqwest::Client = reqwest::Client::new();
+        static ref CLIENT: reqwest::Client = {
+            let mut builder = reqwest::Client::builder();
+            if let Some(timeout) = api_connect_timeout() {
+                builder = builder.connect_timeout(timeout);
+            }
+            builder.build().unwrap_or_default()
+        };
+        // Responses by URL. Anything here is also on disk, so it survives a restart.
+        static ref CACHE: Mutex<HashMap<String, CachedResponse>> = Mutex::new(HashMap::new());
+        static ref BREAKER: Mutex<CircuitBreaker> = Mutex::new(CircuitBreaker::default());
+    }
+
+    /**
+     * Keeps track of whether the API keeps failing. Once enough requests fail in a row the
+     * breaker opens, and requests fail straight away (so callers fall back to cached or installed
+     * data) instead of each waiting out its own timeouts. After a cooldown the next request is
+     * let through: if it succeeds the breaker closes, if it fails the cooldown starts over.
+     */
+    #[derive(Debug, Default)]
+    struct CircuitBreaker {
+        failures: u32,
+        open_until: Option<Instant>,
+    }
+
+    impl CircuitBreaker {
+        fn check(&self, now: Instant) -> Result<(), Error> {
+            match self.open_until {
+                Some(until) if now < until => Err(ResponseError::new(
+                    ErrorKind::ApiOffline,
+                    "Devcade API is offline",
+                )
+                .with_details(format!("trying again in {}s", (until - now).as_secs() + 1))
+                .into()),
+                _ => Ok(()),
+            }
+        }
+
+        /**
+         * Record how a request went. Returns whether the API is online if that just changed.
+         */
+        fn record(&mut self, success: bool, now: Instant) -> Option<bool> {
+            let was_online = self.open_until.is_none();
+            if success {
+                self.failures = 0;
+                self.open_until = None;
+            } else {
+                self.failures = self.failures.saturating_add(1);
+                if self.failures >= FAILURES_BEFORE_OFFLINE {
+                    self.open_until = Some(now + OFFLINE_COOLDOWN);
+                }
+            }
+            let online = self.open_until.is_none();
+            (online != was_online).then_some(online)
+        }
+    }
+
+    /**
+     * Record how a request to the API went, telling subscribers when the API goes offline or
+     * comes back
+     */
+    fn record(success: bool) {
+        let Some(online) = BREAKER.lock().unwrap().record(success, Instant::now()) else {
+            return;
+        };
+        if online {
+            log!(Level::Info, "Devcade API is back online");
+        } else {
+            log!(
+                Level::Warn,
+                "Devcade API is offline after {} failed requests, using cached data for {}s",
+                FAILURES_BEFORE_OFFLINE,
+                OFFLINE_COOLDOWN.as_secs()
+            );
+        }
+        crate::events::publish(Event::ApiConnectivityChanged { online });
+    }
+
+    /**
+     * A JSON response from the API, with what is needed to ask the API whether it changed
+     */
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct CachedResponse {
+        body: String,
+        etag: Option<String>,
+        last_modified: Option<String>,
+        /// When the API last confirmed this is current, in seconds since the unix epoch
+        fetched_at: u64,
+    }
+
+    impl CachedResponse {
+        fn parse<T: for<'de> Deserialize<'de>>(&self) -> Result<Fetched<T>, Error> {
+            Ok(Fetched {
+                value: parse_json(&self.body)?,
+                stale_since: None,
+            })
+        }
+    }
+
+    fn parse_json<T: for<'de> Deserialize<'de>>(body: &str) -> Result<T, Error> {
+        serde_json::from_str(body).map_err(|err| {
+            ResponseError::new(
+                ErrorKind::ApiError,
+                "Couldn't read the response from the Devcade API",
+            )
+            .with_details(err)
+            .into()
+        })
+    }
+
+    /**
+     * Path of the on-disk copy of a cached response
+     */
+    fn cache_path(cache_dir: &Path, url: &str) -> PathBuf {
+        cache_dir.join(format!("{}.json", sha256::digest(url)))
+    }
+
+    /**
+     * Get the cached response for a URL, from memory or from disk
+     */
+    async fn cached(cache_dir: &Path, url: &str) -> Option<CachedResponse> {
+        if let Some(cached) = CACHE.lock().unwrap().get(url) {
+            return Some(cached.clone());
+        }
+        let contents = tokio::fs::read(cache_path(cache_dir, url)).await.ok()?;
+        let cached: CachedResponse = serde_json::from_slice(&contents).ok()?;
+        CACHE
+            .lock()
+            .unwrap()
+            .insert(url.to_string(), cached.clone());
+        Some(cached)
+    }
+
+    /**
+     * Remember a response in memory and on disk. Failing to write it to disk only means it won't
+     * survive a restart.
+     */
+    async fn store(cache_dir: &Path, url: &str, cached: CachedResponse) {
+        let path = cache_path(cache_dir, url);
+        let written = async {
+            if let Some(dir) = path.parent() {
+                tokio::fs::create_dir_all(dir).await?;
+            }
+            let tmp = path.with_extension("json.tmp");
+            tokio::fs::write(&tmp, serde_json::to_vec(&cached)?).await?;
+            tokio::fs::rename(&tmp, &path).await?;
+            Ok::<(), Error>(())
+        };
+        if let Err(err) = written.await {
+            log!(Level::Warn, "Couldn't write API cache for {}: {}", url, err);
+        }
+        CACHE.lock().unwrap().insert(url.to_string(), cached);
     }
 
     /**
-     * Request JSON from a URL and serialize it into a struct
+     * Use a cached response because the API couldn't give us a current one, marking it stale and
+     * telling subscribers that it may be out of date. Without a cached response, the original
+     * error is returned.
+     */
+    fn serve_stale<T: for<'de> Deserialize<'de>>(
+        url: &str,
+        cached: Option<CachedResponse>,
+        err: Error,
+    ) -> Result<Fetched<T>, Error> {
+        let Some(cached) = cached else {
+            return Err(err);
+        };
+        log!(
+            Level::Warn,
+            "Serving cached response for {} from {}: {}",
+            url,
+            cached.fetched_at,
+            err
+        );
+        crate::events::publish(Event::StaleApiData {
+            url: url.to_string(),
+            fetched_at: cached.fetched_at,
+        });
+        Ok(Fetched {
+            stale_since: Some(cached.fetched_at),
+            ..cached.parse()?
+        })
+    }
+
+    /**
+     * Error for a request that never got an answer from the API
+     */
+    pub fn offline(err: reqwest::Error) -> Error {
+        ResponseError::new(ErrorKind::ApiOffline, "Couldn't reach the Devcade API")
+            .with_details(err)
+            .into()
+    }
+
+    /**
+     * Error for an answer from the API that couldn't be read
+     */
+    pub fn bad_response(err: impl ToString) -> Error {
+        ResponseError::new(
+            ErrorKind::ApiError,
+            "Couldn't read the response from the Devcade API",
+        )
+        .with_details(err)
+        .into()
+    }
+
+    /**
+     * Wait for the API to send something, giving up if it takes longer than
+     * DEVCADE_API_READ_TIMEOUT
+     */
+    async fn read<T>(answer: impl Future<Output = Result<T, reqwest::Error>>) -> Result<T, Error> {
+        let result = match api_read_timeout() {
+            Some(timeout) => tokio::time::timeout(timeout, answer).await.map_err(|_| {
+                Error::from(
+                    ResponseError::new(ErrorKind::ApiOffline, "Devcade API stopped answering")
+                        .with_details(format!("nothing received for {}s", timeout.as_secs())),
+                )
+            })?,
+            None => answer.await,
+        };
+        result.map_err(offline)
+    }
+
+    /**
+     * Whether a request that got this answer should be sent again
+     */
+    fn should_retry(status: StatusCode) -> bool {
+        status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+    }
+
+    /**
+     * Send a GET request, built from the client by `build`. If the API can't be reached or answers
+     * with a server error, the request is sent again after a delay that doubles every attempt.
+     * Every request to the API is a GET, which doesn't change anything, so this is always safe.
+     * The last answer is returned even if it is an error status, for the caller to handle.
+     *
+     * # Errors
+     * This function will return an error if the API is offline, or if it couldn't be reached on
+     * any attempt.
+     */
+    async fn get(
+        url: &str,
+        build: impl Fn(RequestBuilder) -> RequestBuilder,
+    ) -> Result<reqwest::Response, Error> {
+        BREAKER.lock().unwrap().check(Instant::now())?;
+
+        let mut backoff = INITIAL_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            let result = read(build(CLIENT.deref().get(url)).send()).await;
+            let failure = match &result {
+                Ok(response) if !should_retry(response.status()) => {
+                    record(true);
+                    return result;
+                }
+                _ if attempt >= MAX_ATTEMPTS => {
+                    record(false);
+                    return result;
+                }
+                Ok(response) => format!("Devcade API answered {}", response.status()),
+                Err(err) => err.to_string(),
+            };
+            log!(
+                Level::Debug,
+                "Request to {} failed (attempt {}/{}), trying again in {}ms: {}",
+                url,
+                attempt,
+                MAX_ATTEMPTS,
+                backoff.as_millis(),
+                failure
+            );
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+
+    /**
+     * Turn an answer from the API that isn't a success into an error
+     */
+    fn check_status(url: &str, response: reqwest::Response) -> Result<reqwest::Response, Error> {
+        match response.status() {
+            status if status.is_success() => Ok(response),
+            StatusCode::NOT_FOUND => Err(ResponseError::new(
+                ErrorKind::NotFound,
+                "Devcade API couldn't find it",
+            )
+            .with_details(url)
+            .into()),
+            status => Err(ResponseError::new(
+                ErrorKind::ApiError,
+                format!("Devcade API answered {status}"),
+            )
+            .with_details(url)
+            .into()),
+        }
+    }
+
+    /**
+     * Read the next chunk of a response's body, giving up if the API sends nothing for longer than
+     * DEVCADE_API_READ_TIMEOUT. Returns `None` once the whole body has been read.
+     *
+     * # Errors
+     * This function will return an error if the connection fails or times out.
+     */
+    pub async fn next_chunk(
+        response: &mut reqwest::Response,
+    ) -> Result<Option<impl Deref<Target = [u8]>>, Error> {
+        let chunk = read(response.chunk()).await;
+        if chunk.is_err() {
+            record(false);
+        }
+        chunk
+    }
+
+    /**
+     * Read the whole body of a response
+     */
+    async fn read_body(mut response: reqwest::Response) -> Result<Vec<u8>, Error> {
+        let mut body = Vec::new();
+        while let Some(chunk) = next_chunk(&mut response).await? {
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body)
+    }
+
+    /**
+     * Request JSON from a URL and serialize it into a struct. Responses are cached in memory and
+     * in DEVCADE_API_CACHE_DIR. A cached response younger than DEVCADE_API_CACHE_TTL is used as is,
+     * an older one is revalidated with the API (using its ETag or Last-Modified date). If the API
+     * can't be reached or fails, the cached response is used anyway, marked stale, and a
+     * [`Event::StaleApiData`] is published.
      *
      * # Errors
-     * This function will return an error if the request fails, or if the JSON cannot be deserialized
+     * This function will return an error if the request fails and nothing is cached, if the API
+     * doesn't know the requested object or refuses the request, or if the JSON cannot be
+     * deserialized
+     */
+    pub async fn request_json<T: for<'de> Deserialize<'de>>(
+        url: &str,
+    ) -> Result<Fetched<T>, Error> {
+        fetch_json(url, &api_cache_dir(), api_cache_ttl()).await
+    }
+
+    /**
+     * [`request_json`] with the cache in `cache_dir`, using cached responses younger than `ttl`
      */
-    pub async fn request_json<T: for<'de> Deserialize<'de>>(url: &str) -> Result<T, Error> {
+    async fn fetch_json<T: for<'de> Deserialize<'de>>(
+        url: &str,
+        cache_dir: &Path,
+        ttl: Duration,
+    ) -> Result<Fetched<T>, Error> {
+        let cached = cached(cache_dir, url).await;
+        let now = unix_secs(SystemTime::now());
+        if let Some(cached) = &cached {
+            if now.saturating_sub(cached.fetched_at) < ttl.as_secs() {
+                log!(Level::Trace, "Using cached JSON for {}", url);
+                return cached.parse();
+            }
+        }
+
         log!(Level::Trace, "Requesting JSON from {}", url);
-        let response = CLIENT.deref().get(url).send().await?;
-        let json = response.json().await?;
-        Ok(json)
+        let revalidate = |mut request: RequestBuilder| {
+            if let Some(cached) = &cached {
+                if let Some(etag) = &cached.etag {
+                    request = request.header(IF_NONE_MATCH, etag);
+                }
+                if let Some(last_modified) = &cached.last_modified {
+                    request = request.header(IF_MODIFIED_SINCE, last_modified);
+                }
+            }
+            request
+        };
+        let response = match get(url, revalidate).await {
+            Ok(response) => response,
+            Err(err) => return serve_stale(url, cached, err),
+        };
+
+        let status = response.status();
+        if status == StatusCode::NOT_MODIFIED {
+            if let Some(mut cached) = cached {
+                cached.fetched_at = now;
+                store(cache_dir, url, cached.clone()).await;
+                return cached.parse();
+            }
+        }
+        let response = match check_status(url, response) {
+            Ok(response) => response,
+            Err(err) if status.is_server_error() => return serve_stale(url, cached, err),
+            Err(err) => return Err(err),
+        };
+
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let etag = header(ETAG);
+        let last_modified = header(LAST_MODIFIED);
+        let body = match read_body(response).await {
+            Ok(body) => body,
+            Err(err) => return serve_stale(url, cached, err),
+        };
+        let body = String::from_utf8(body).map_err(bad_response)?;
+        // Only cache what could be read, so a broken response doesn't replace a good one
+        let value = parse_json(&body)?;
+        store(
+            cache_dir,
+            url,
+            CachedResponse {
+                body,
+                etag,
+                last_modified,
+                fetched_at: now,
+            },
+        )
+        .await;
+        Ok(Fetched {
+            value,
+            stale_since: None,
+        })
     }
 
     /**
      * Request binary data from a URL
      *
      * # Errors
-     * This function will return an error if the request fails.
+     * This function will return an error if the request fails, or if the API doesn't answer with
+     * the data.
      */
     pub async fn request_bytes(url: &str) -> Result<Vec<u8>, Error> {
         log!(Level::Trace, "Requesting binary from {}", url);
-        let response = CLIENT.deref().get(url).send().await?;
-        let bytes = response.bytes().await?;
-        Ok(bytes.to_vec())
+        let response = check_status(url, get(url, |request| request).await?)?;
+        read_body(response).await
+    }
+
+    /**
+     * Start a request for binary data from a URL without reading the body, so that it can be read
+     * in chunks with [`next_chunk`].
+     *
+     * # Errors
+     * This function will return an error if the request fails, or if the API doesn't answer with
+     * the data.
+     */
+    pub async fn request_stream(url: &str) -> Result<reqwest::Response, Error> {
+        log!(Level::Trace, "Requesting stream from {}", url);
+        check_status(url, get(url, |request| request).await?)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use hyper::header::HeaderValue;
+        use hyper::service::{make_service_fn, service_fn};
+        use hyper::{Body, Request, Response, Server};
+        use std::convert::Infallible;
+        use std::sync::Arc;
+        use tempfile::TempDir;
+
+        const ETAG_V1: &str = "\"v1\"";
+
+        /**
+         * What the mock API has been asked, and whether it is failing
+         */
+        #[derive(Debug, Default)]
+        struct MockApi {
+            requests: u32,
+            not_modified: u32,
+            failing: bool,
+        }
+
+        type MockState = Arc<Mutex<MockApi>>;
+
+        /**
+         * Start an API that always answers with the same list, tagged with an ETag
+         */
+        async fn mock_api() -> (String, MockState) {
+            let state = MockState::default();
+            let shared = state.clone();
+            let service = make_service_fn(move |_| {
+                let state = shared.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req| api_request(state.clone(), req)))
+                }
+            });
+            let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(service);
+            let url = format!("http://{}/tags/", server.local_addr());
+            tokio::spawn(server);
+            (url, state)
+        }
+
+        async fn api_request(
+            state: MockState,
+            req: Request<Body>,
+        ) -> Result<Response<Body>, Infallible> {
+            let mut state = state.lock().unwrap();
+            state.requests += 1;
+            if state.failing {
+                return Ok(Response::builder().status(500).body(Body::empty()).unwrap());
+            }
+            if req.headers().get(IF_NONE_MATCH) == Some(&HeaderValue::from_static(ETAG_V1)) {
+                state.not_modified += 1;
+                return Ok(Response::builder().status(304).body(Body::empty()).unwrap());
+            }
+            Ok(Response::builder()
+                .header(ETAG, ETAG_V1)
+                .body(Body::from("[1, 2, 3]"))
+                .unwrap())
+        }
+
+        #[tokio::test]
+        async fn responses_are_cached_until_they_expire() {
+            let (url, api) = mock_api().await;
+            let dir = TempDir::new().unwrap();
+            let ttl = Duration::from_secs(60);
+
+            let fetched: Fetched<Vec<u32>> = fetch_json(&url, dir.path(), ttl).await.unwrap();
+            assert_eq!(fetched.value, [1, 2, 3]);
+            assert_eq!(fetched.stale_since, None);
+            // Fresh responses are used without asking the API, even after a restart
+            fetch_json::<Vec<u32>>(&url, dir.path(), ttl).await.unwrap();
+            CACHE.lock().unwrap().remove(&url);
+            let cached: Fetched<Vec<u32>> = fetch_json(&url, dir.path(), ttl).await.unwrap();
+            assert_eq!(cached.value, [1, 2, 3]);
+            assert_eq!(api.lock().unwrap().requests, 1);
+        }
+
+        #[tokio::test]
+        async fn expired_responses_are_revalidated() {
+            let (url, api) = mock_api().await;
+            let dir = TempDir::new().unwrap();
+
+            fetch_json::<Vec<u32>>(&url, dir.path(), Duration::ZERO)
+                .await
+                .unwrap();
+            let revalidated: Fetched<Vec<u32>> =
+                fetch_json(&url, dir.path(), Duration::ZERO).await.unwrap();
+            assert_eq!(revalidated.value, [1, 2, 3]);
+            assert_eq!(revalidated.stale_since, None);
+            let api = api.lock().unwrap();
+            assert_eq!((api.requests, api.not_modified), (2, 1));
+        }
+
+        #[tokio::test]
+        async fn cached_responses_are_served_stale_when_the_api_fails() {
+            let (url, api) = mock_api().await;
+            let dir = TempDir::new().unwrap();
+
+            fetch_json::<Vec<u32>>(&url, dir.path(), Duration::ZERO)
+                .await
+                .unwrap();
+            let fetched_at = CACHE.lock().unwrap()[&url].fetched_at;
+            api.lock().unwrap().failing = true;
+            let stale: Fetched<Vec<u32>> =
+                fetch_json(&url, dir.path(), Duration::ZERO).await.unwrap();
+            assert_eq!(stale.value, [1, 2, 3]);
+            assert_eq!(stale.stale_since, Some(fetched_at));
+            assert_eq!(api.lock().unwrap().requests, 1 + MAX_ATTEMPTS);
+        }
+
+        #[test]
+        fn breaker_opens_after_repeated_failures() {
+            let mut breaker = CircuitBreaker::default();
+            let now = Instant::now();
+            for _ in 1..FAILURES_BEFORE_OFFLINE {
+                assert_eq!(breaker.record(false, now), None);
+            }
+            assert!(breaker.check(now).is_ok());
+
+            assert_eq!(breaker.record(false, now), Some(false));
+            assert!(breaker.check(now).is_err());
+            // Once the cooldown is over a request is let through, and one success closes it again
+            assert!(breaker.check(now + OFFLINE_COOLDOWN).is_ok());
+            assert_eq!(breaker.record(true, now + OFFLINE_COOLDOWN), Some(true));
+            assert_eq!(breaker.failures, 0);
+        }
+
+        #[test]
+        fn failed_checks_reopen_the_breaker() {
+            let mut breaker = CircuitBreaker::default();
+            let now = Instant::now();
+            for _ in 0..FAILURES_BEFORE_OFFLINE {
+                breaker.record(false, now);
+            }
+
+            let later = now + OFFLINE_COOLDOWN;
+            assert_eq!(breaker.record(false, later), None);
+            assert!(breaker.check(later).is_err());
+            assert!(breaker.check(later + OFFLINE_COOLDOWN).is_ok());
+        }
+    }
+}
+
+/**
+ * Reports the progress of a game download to the client that requested it (if it asked for
+ * progress) and to any event subscribers. Download progress is throttled to roughly one update
+ * per percent (or per MiB if the size is unknown) so a large game doesn't flood the socket.
+ */
+struct DownloadReporter {
+    game_id: String,
+    progress: Option<ProgressSender>,
+    last_reported: u64,
+}
+
+impl DownloadReporter {
+    const MIN_STEP: u64 = 1024 * 1024;
+
+    fn new(game_id: String, progress: Option<ProgressSender>) -> Self {
+        Self {
+            game_id,
+            progress,
+            last_reported: 0,
+        }
+    }
+
+    fn downloading(&mut self, received: u64, total: Option<u64>) {
+        let step = total.map_or(Self::MIN_STEP, |total| total / 100).max(1);
+        if received - self.last_reported < step && Some(received) != total && received != 0 {
+            return;
+        }
+        self.last_reported = received;
+
+        crate::events::publish(Event::DownloadProgress {
+            game_id: self.game_id.clone(),
+            received,
+            total,
+        });
+        if let Some(progress) = &self.progress {
+            // The client going away mid-download isn't our problem
+            let _ = progress.send(Progress::Downloading { received, total });
+        }
     }
 }
 
@@ -149,13 +836,15 @@ mod route {
  * # Errors
  * This function will return an error if the request fails, or if the JSON cannot be deserialized
  */
-pub async fn game_list() -> Result<Vec<DevcadeGame>, Error> {
-    let games: Vec<DevcadeGame> =
+pub async fn game_list() -> Result<Fetched<Vec<DevcadeGame>>, Error> {
+    let games: Fetched<Vec<DevcadeGame>> =
         network::request_json(format!("{}/{}", api_url(), route::game_list()).as_str()).await?;
-    Ok(games
-        .into_iter()
-        .filter(|game| game.hash.is_some())
-        .collect::<Vec<DevcadeGame>>())
+    Ok(games.map(|games| {
+        games
+            .into_iter()
+            .filter(|game| game.hash.is_some())
+            .collect::<Vec<DevcadeGame>>()
+    }))
 }
 
 /**
@@ -164,9 +853,8 @@ pub async fn game_list() -> Result<Vec<DevcadeGame>, Error> {
  * # Errors
  * This function will return an error if the request fails, or if the JSON cannot be deserialized
  */
-pub async fn get_game(id: &str) -> Result<DevcadeGame, Error> {
-    let game = network::request_json(format!("{}/{}", api_url(), route::game(id)).as_str()).await?;
-    Ok(game)
+pub async fn get_game(id: &str) -> Result<Fetched<DevcadeGame>, Error> {
+    network::request_json(format!("{}/{}", api_url(), route::game(id)).as_str()).await
 }
 
 /**
@@ -200,6 +888,76 @@ pub fn game_list_from_fs() -> Result<Vec<DevcadeGame>, Error> {
     Ok(games)
 }
 
+/**
+ * Get the list of tags used by games installed on the filesystem, sorted by name. This can be used
+ * if the API is down.
+ *
+ * # Errors
+ * This function will return an error if the filesystem cannot be read at the DEVCADE_PATH location.
+ */
+pub fn tag_list_from_fs() -> Result<Vec<Tag>, Error> {
+    let mut tags = BTreeMap::new();
+    for game in game_list_from_fs()? {
+        for tag in game.tags {
+            tags.entry(tag.name.clone()).or_insert(tag);
+        }
+    }
+    Ok(tags.into_values().collect())
+}
+
+/**
+ * Get a tag used by a game installed on the filesystem. This can be used if the API is down.
+ *
+ * # Errors
+ * This function will return an error if the filesystem cannot be read at the DEVCADE_PATH
+ * location, or if no installed game has the tag.
+ */
+pub fn tag_from_fs(name: &str) -> Result<Tag, Error> {
+    tag_list_from_fs()?
+        .into_iter()
+        .find(|tag| tag.name == name)
+        .ok_or_else(|| {
+            error(
+                ErrorKind::NotFound,
+                format!("No installed game has the tag {name}"),
+            )
+        })
+}
+
+/**
+ * Get the games installed on the filesystem that have the given tag. This can be used if the API
+ * is down.
+ *
+ * # Errors
+ * This function will return an error if the filesystem cannot be read at the DEVCADE_PATH location.
+ */
+pub fn tag_games_from_fs(name: &str) -> Result<Vec<DevcadeGame>, Error> {
+    Ok(game_list_from_fs()?
+        .into_iter()
+        .filter(|game| game.tags.iter().any(|tag| tag.name == name))
+        .collect())
+}
+
+/**
+ * Get a user who uploaded a game installed on the filesystem. This can be used if the API is down.
+ *
+ * # Errors
+ * This function will return an error if the filesystem cannot be read at the DEVCADE_PATH
+ * location, or if the user didn't upload any installed game.
+ */
+pub fn user_from_fs(uid: &str) -> Result<User, Error> {
+    game_list_from_fs()?
+        .into_iter()
+        .map(|game| game.user)
+        .find(|user| user.id == uid)
+        .ok_or_else(|| {
+            error(
+                ErrorKind::NotFound,
+                format!("No installed game was uploaded by user {uid}"),
+            )
+        })
+}
+
 /**
  * Download's a game's banner from the API.
  *
@@ -253,44 +1011,138 @@ pub async fn download_icon(game_id: String) -> Result<(), Error> {
     Ok(())
 }
 
+/**
+ * Wait for a member to present their tag, returning their handle for the current game
+ *
+ * # Errors
+ * This function will return an error if the reader doesn't exist (only player 1 has one), or if
+ * the NFC thread can't be reached.
+ */
 pub async fn nfc_tags(reader_id: Player) -> Result<Option<String>, Error> {
-    assert!(reader_id == Player::P1);
-    NFC_CLIENT
-        .submit()
-        .await
-        .map_err(|err| anyhow!("Couldn't get NFC tags: {:?}", err))
+    if reader_id != Player::P1 {
+        return Err(error(
+            ErrorKind::InvalidRequest,
+            format!("There is no NFC reader for player {reader_id}"),
+        ));
+    }
+    let association_id = NFC_CLIENT.submit().await.map_err(|err| {
+        ResponseError::new(ErrorKind::Nfc, "Couldn't get NFC tags").with_details(format!("{err:?}"))
+    })?;
+    if let Some(association_id) = &association_id {
+        supervisor::identify_player(association_id);
+    }
+    Ok(association_id)
 }
 
 pub async fn nfc_user(association_id: String) -> Result<Map<String, Value>, Error> {
     NFC_CLIENT
         .get_user(association_id)
         .await
-        .map_err(|err| anyhow!("Couldn't get NFC user: {:?}", err))
+        .map_err(|err| match err.downcast::<ResponseError>() {
+            Ok(err) => err.into(),
+            Err(err) => ResponseError::new(ErrorKind::Nfc, "Couldn't get NFC user")
+                .with_details(format!("{err:?}"))
+                .into(),
+        })
+}
+
+/**
+ * Cancels a `gio::Cancellable` when dropped. Used to stop work running on another thread when the
+ * future waiting for it is dropped (e.g. because the request was cancelled).
+ */
+struct CancelOnDrop(gio::Cancellable);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
 }
 
-async fn install_flatpak_bundle_async(bundle_path: PathBuf) -> Result<String, Error> {
+/**
+ * Asks a game to stop when dropped, if it is still running. Used to stop a game when the request
+ * that launched it is cancelled.
+ */
+struct StopGameOnDrop(Option<String>);
+
+impl Drop for StopGameOnDrop {
+    fn drop(&mut self) {
+        let Some(game_id) = self.0.take() else {
+            return;
+        };
+        if matches!(supervisor::running_game(), Some(running) if running.game_id == game_id) {
+            if let Err(err) = supervisor::kill() {
+                log::warn!("Couldn't stop game {game_id}: {err}");
+            }
+        }
+    }
+}
+
+async fn install_flatpak_bundle_async(
+    bundle_path: PathBuf,
+    progress: Option<ProgressSender>,
+) -> Result<String, Error> {
     let (tx, rx) = oneshot::channel();
+    let cancellable = gio::Cancellable::new();
+    // If this future is dropped before the install finishes, the transaction is cancelled and
+    // flatpak rolls back whatever it had done so far. Cancelling a finished install is a no-op.
+    let _guard = CancelOnDrop(cancellable.clone());
     std::thread::spawn(move || {
-        tx.send(install_flatpak_bundle(&bundle_path))
-            .expect("Server thread died before we could send flatpak install response?")
+        // Nobody is listening anymore if the request was cancelled
+        let _ = tx.send(install_flatpak_bundle(&bundle_path, progress, &cancellable));
     });
     match rx.await {
         Ok(result) => result,
-        Err(err) => Err(err.into()),
+        Err(err) => Err(ResponseError::new(
+            ErrorKind::InstallFailed,
+            "Flatpak install thread died before finishing",
+        )
+        .with_details(err)
+        .into()),
     }
 }
 
-fn install_flatpak_bundle(bundle_path: &Path) -> Result<String, Error> {
+fn install_flatpak_bundle(
+    bundle_path: &Path,
+    progress: Option<ProgressSender>,
+    cancellable: &gio::Cancellable,
+) -> Result<String, Error> {
+    let install_failed = |err: gio::glib::Error| {
+        ResponseError::new(
+            ErrorKind::InstallFailed,
+            "Flatpak couldn't install the game",
+        )
+        .with_details(err)
+    };
     let transaction = Transaction::for_installation(
-        &Installation::new_user(None::<&gio::Cancellable>)?,
-        None::<&gio::Cancellable>,
-    )?;
+        &Installation::new_user(Some(cancellable)).map_err(install_failed)?,
+        Some(cancellable),
+    )
+    .map_err(install_failed)?;
     transaction.set_no_pull(false);
     transaction.set_no_interaction(true);
     transaction.add_default_dependency_sources();
-    transaction.add_install_bundle(&gio::File::for_path(bundle_path), None)?;
+    transaction
+        .add_install_bundle(&gio::File::for_path(bundle_path), None)
+        .map_err(install_failed)?;
     transaction.set_reinstall(true);
+    if let Some(progress) = progress {
+        transaction.connect_new_operation(move |_, _operation, operation_progress| {
+            let progress = progress.clone();
+            operation_progress.connect_changed(move |operation_progress| {
+                let _ = progress.send(Progress::Installing {
+                    status: operation_progress
+                        .status()
+                        .map(|status| status.to_string())
+                        .unwrap_or_default(),
+                    percent: operation_progress.progress().clamp(0, 100) as u8,
+                });
+            });
+        });
+    }
     let (tx_app_id, rx_app_id) = std::sync::mpsc::channel::<String>();
+    // Set if the install was aborted because the app asks for too much
+    let rejected = Rc::new(RefCell::new(None::<String>));
+    let rejected_ = rejected.clone();
     transaction.connect_ready(move |transaction| {
         // Return false to abort!
         let mut app_name = None::<String>;
@@ -315,6 +1167,10 @@ fn install_flatpak_bundle(bundle_path: &Path) -> Result<String, Error> {
                     }
                     Ok(false) => {
                         log::error!("Aborting installation of {name:?}");
+                        *rejected_.borrow_mut() = Some(format!(
+                            "Game {} asks for flatpak permissions that aren't allowed",
+                            name.as_deref().unwrap_or("(unnamed)")
+                        ));
                         return false;
                     }
                     Err(err) => {
@@ -323,16 +1179,33 @@ fn install_flatpak_bundle(bundle_path: &Path) -> Result<String, Error> {
                     }
                 }
             } else {
-                println!("no data for {:?}", op.bundle_path());
+                log::debug!("No metadata for bundle {:?}", op.bundle_path());
             }
         }
-        tx_app_id.send(app_name.unwrap()).unwrap();
+        if let Some(app_name) = app_name {
+            // Unwrap rationale: the receiver lives until the transaction is done
+            tx_app_id.send(app_name).unwrap();
+        }
         // looks like we're good!
         true
     });
-    transaction.run(None::<&gio::Cancellable>)?;
-    Ok(rx_app_id.recv().unwrap())
-    //Ok("todo".to_owned())
+    if let Err(err) = transaction.run(Some(cancellable)) {
+        if cancellable.is_cancelled() {
+            return Err(error(ErrorKind::Cancelled, "Flatpak install was cancelled"));
+        }
+        if let Some(reason) = rejected.borrow_mut().take() {
+            return Err(ResponseError::new(ErrorKind::PermissionRejected, reason)
+                .with_details(err)
+                .into());
+        }
+        return Err(install_failed(err).into());
+    }
+    rx_app_id.recv().map_err(|_| {
+        error(
+            ErrorKind::InstallFailed,
+            "Flatpak bundle doesn't contain an application name",
+        )
+    })
 }
 
 fn is_install_allowed(metadata: &gio::glib::KeyFile) -> Result<bool, Error> {
@@ -377,14 +1250,20 @@ fn is_install_allowed(metadata: &gio::glib::KeyFile) -> Result<bool, Error> {
 }
 
 /**
- * Download's a game's zip file from the API and unzips it into the game's directory. If the game is
- * already downloaded, it will check if the hash is the same. If it is, it will not download the game
+ * Download's a game's flatpak bundle from the API and installs it. If the game is already
+ * downloaded, it will check if the hash is the same. If it is, it will not download the game
  * again.
  *
+ * The bundle is streamed to disk, and if `progress` is given, both the download and the install
+ * phase are reported through it.
+ *
  * # Errors
  * This function will return an error if the request fails, or if the filesystem cannot be written to.
  */
-pub async fn download_game(game_id: String) -> Result<DevcadeGame, Error> {
+pub async fn download_game(
+    game_id: String,
+    progress: Option<ProgressSender>,
+) -> Result<DevcadeGame, Error> {
     log::debug!("Downloading a game!");
     let game_dir = Path::new(devcade_path().as_str()).join(game_id.clone());
     let game_json_path = game_dir.join("game.json");
@@ -393,14 +1272,15 @@ pub async fn download_game(game_id: String) -> Result<DevcadeGame, Error> {
     let mut game = match get_game(game_id.as_str()).await {
         Ok(game) => {
             log::debug!("Fetched game meta!");
-            game
+            game.value
         }
         Err(err) => {
             log::warn!("Couldn't request live info on game! Falling back to local file! {err:?}");
-            local_game
-                .as_ref()
-                .expect("Game not downloaded and we're offline!")
-                .clone()
+            match &local_game {
+                Ok(local_game) => local_game.clone(),
+                // Not downloaded and we're offline (or the game doesn't exist)
+                Err(_) => return Err(err),
+            }
         }
     };
     // Is the current hash == the remote hash?
@@ -412,21 +1292,35 @@ pub async fn download_game(game_id: String) -> Result<DevcadeGame, Error> {
 
     log!(Level::Info, "Downloading game {}...", game.name);
 
-    let bytes = network::request_bytes(
+    tokio::fs::create_dir_all(&game_dir).await?;
+    let bundle_path = game_dir.join("bundle.flatpak");
+
+    let mut response = network::request_stream(
         format!("{}/{}", api_url(), route::game_download(game_id.as_str())).as_str(),
     )
     .await?;
+    let total = response.content_length();
+    let mut reporter = DownloadReporter::new(game_id.clone(), progress.clone());
+    reporter.downloading(0, total);
+
+    let mut file = fs::File::create(&bundle_path).await?;
+    let mut received = 0;
+    while let Some(chunk) = network::next_chunk(&mut response).await? {
+        file.write_all(&chunk).await?;
+        received += chunk.len() as u64;
+        reporter.downloading(received, total);
+    }
+    file.sync_all().await?;
+    if total != Some(received) {
+        // Size was unknown (or wrong), so the last chunk wasn't reported as the end
+        reporter.downloading(received, Some(received));
+    }
 
-    log!(Level::Info, " game {}...", game.name);
-    log!(Level::Trace, "Flatpak bundle size: {} bytes", bytes.len());
-
-    // // install flatpak
-    tokio::fs::create_dir_all(&game_dir).await?;
-    let bundle_path = game_dir.join("bundle.flatpak").to_owned();
-    tokio::fs::write(&bundle_path, &bytes).await?;
+    log!(Level::Info, "Installing game {}...", game.name);
+    log!(Level::Trace, "Flatpak bundle size: {} bytes", received);
 
-    game.flatpak_app_id = Some(install_flatpak_bundle_async(bundle_path).await?);
-    log::info!("Hi, flatpak app id {:?}", game.flatpak_app_id);
+    game.flatpak_app_id = Some(install_flatpak_bundle_async(bundle_path, progress).await?);
+    log::debug!("Installed flatpak app {:?}", game.flatpak_app_id);
 
     // Write the game's JSON file to the game's directory (this is used later to get the games from
     // the filesystem)
@@ -455,8 +1349,7 @@ pub async fn download_game(game_id: String) -> Result<DevcadeGame, Error> {
 
 /**
  * Launch a game by its ID. This will check if the game is downloaded, and if it is, it will launch
- * the game. This returns a `JoinHandle`, which should be used to check for game exit and notify the
- * backend.
+ * the game. The game is run by the `supervisor`, and this returns once the game has exited.
  *
  * # Errors
  * This function will return an error if the filesystem cannot be read from,
@@ -475,13 +1368,17 @@ pub async fn launch_game(game_id: String) -> Result<(), Error> {
     log!(Level::Trace, "Game path: {}", path.to_str().unwrap());
 
     // Downloads game if we don't already have it
-    let game = download_game(game_id.clone()).await?;
+    let game = download_game(game_id.clone(), None).await?;
 
     // flush data every time a new game is opened (in case previous launched game forgor)
-    match persistence_flush().await {
+    match persistence::flush().await {
         Ok(_) => {}
         Err(e) => log::warn!("Failed to flush save cache: {e}"),
     }
+    // pick up progress made on other cabinets, playing with what we have if that fails
+    if let Err(e) = persistence::pull(&game.id).await {
+        log::warn!("Failed to pull save data for {}: {e}", game.id);
+    }
     CURRENT_GAME.lock().unwrap().set(game.clone());
 
     // needs to be outside command builder because std::env::vars() is not Send
@@ -502,13 +1399,21 @@ pub async fn launch_game(game_id: String) -> Result<(), Error> {
 
     log!(Level::Trace, "Game ENV: {:?}", envs);
 
+    let flatpak_app_id = game.flatpak_app_id.clone().ok_or_else(|| {
+        error(
+            ErrorKind::LaunchFailed,
+            format!("Game {game_id} wasn't installed with flatpak"),
+        )
+    })?;
+
     // Launch the game and silence stdout (allow the game to print to stderr)
-    Command::new("flatpak")
+    let mut command = Command::new("flatpak");
+    command
         .arg("run")
         .arg("--user")
         .arg("--device=dri")
         .arg("--cwd=/app/publish")
-        .arg(game.flatpak_app_id.unwrap())
+        .arg(&flatpak_app_id)
         // Unfortunately this will bypass the log crate, so no pretty logging for games
         .stdout(Stdio::inherit())
         .stderr(std::process::Stdio::inherit())
@@ -516,12 +1421,33 @@ pub async fn launch_game(game_id: String) -> Result<(), Error> {
         .current_dir(path.parent().unwrap())
         // Oops, there's kind of secrets in there
         .env_clear()
-        .envs(envs)
-        .spawn()
-        .expect("Failed to launch game")
-        .wait()
-        .await
-        .expect("Failed to launch game");
+        .envs(envs);
+
+    // The supervisor owns the game until it exits, in a task of its own so that everything that
+    // has to happen when the game ends still happens if this request is cancelled. Cancelling
+    // stops the game the same way `KillGame` does.
+    let mut stop = StopGameOnDrop(Some(game_id.clone()));
+    let supervised = tokio::spawn(async move {
+        // Stops the game if nobody touches the controls for a while
+        let _watchdog =
+            crate::env::idle_timeout().map(|timeout| Watchdog::start(game_id.clone(), timeout));
+
+        let exit = supervisor::run(game_id, &flatpak_app_id, &mut command).await;
+
+        // Whatever the game saved shouldn't wait for the next periodic flush, in case the backend
+        // goes down before then
+        match persistence::flush().await {
+            Ok(_) => {}
+            Err(e) => log::warn!("Failed to flush save cache after game exit: {e}"),
+        }
+        exit
+    });
+    let exit = supervised.await;
+    // The supervisor is done with the game, whichever game may be running now isn't ours
+    stop.0 = None;
+    exit.map_err(|err| {
+        ResponseError::new(ErrorKind::Internal, "Game supervisor failed").with_details(err)
+    })??;
 
     tokio::time::sleep(Duration::from_millis(200)).await;
     Ok(())
@@ -534,7 +1460,7 @@ pub async fn launch_game(game_id: String) -> Result<(), Error> {
  * This function will return an error if the server cannot be reached, or if the server returns an
  * error.
  */
-pub async fn tag_list() -> Result<Vec<Tag>, Error> {
+pub async fn tag_list() -> Result<Fetched<Vec<Tag>>, Error> {
     network::request_json(format!("{}/{}", api_url(), route::tag_list()).as_str()).await
 }
 
@@ -545,40 +1471,116 @@ pub async fn tag_list() -> Result<Vec<Tag>, Error> {
  * This function will return an error if the server cannot be reached, or if the server returns an
  * error.
  */
-pub async fn tag(name: String) -> Result<Tag, Error> {
+pub async fn tag(name: String) -> Result<Fetched<Tag>, Error> {
     network::request_json(format!("{}/{}", api_url(), route::tag(name.as_str())).as_str()).await
 }
 
 /**
- * Returns a list of all games with the given tag
+ * Returns a list of all games with the given tag, in the order the API lists them. Games are taken
+ * from the game list when possible, the rest are fetched one by one, at most
+ * `MAX_CONCURRENT_FETCHES` at a time. Games that couldn't be fetched are listed in `failed`. The
+ * list is stale if anything it was built from is.
  *
  * # Errors
  * This function will return an error if the server cannot be reached, or if the server returns an
  * error.
  */
-pub async fn tag_games(name: String) -> Result<Vec<DevcadeGame>, Error> {
-    let games: Vec<MinimalGame> = network::request_json(
+pub async fn tag_games(name: String) -> Result<Fetched<PartialGameList>, Error> {
+    let listed: Fetched<Vec<MinimalGame>> = network::request_json(
         format!("{}/{}", api_url(), route::tag_games(name.as_str())).as_str(),
     )
     .await?;
-    let games: Vec<_> = games.into_iter().map(game_from_minimal).collect();
-    // await all the games and return them
-    let games: Vec<Result<DevcadeGame, Error>> = futures_util::future::join_all(games).await;
-    Ok(games
-        .into_iter()
-        .filter_map(|g| {
-            if let Ok(g) = g {
-                Some(g)
-            } else {
+
+    let known = match game_list().await {
+        Ok(games) => games.map(|games| {
+            games
+                .into_iter()
+                .map(|game| (game.id.clone(), game))
+                .collect()
+        }),
+        Err(err) => {
+            log!(Level::Warn, "Couldn't get game list for tag {name}: {err}");
+            Fetched {
+                value: HashMap::new(),
+                stale_since: None,
+            }
+        }
+    };
+
+    let resolved = resolve_tag_games(&name, listed.value, &known.value, |id| async move {
+        get_game(&id).await
+    })
+    .await;
+    Ok(Fetched {
+        stale_since: oldest([listed.stale_since, known.stale_since, resolved.stale_since]),
+        ..resolved
+    })
+}
+
+/**
+ * Turn the games the API lists for a tag into full games, keeping the order they are listed in.
+ * Games in `known` are used as they are, the rest are fetched with `fetch`, at most
+ * `MAX_CONCURRENT_FETCHES` at a time. Games that couldn't be fetched are listed in `failed`,
+ * sorted by ID.
+ */
+async fn resolve_tag_games<F, Fut>(
+    name: &str,
+    listed: Vec<MinimalGame>,
+    known: &HashMap<String, DevcadeGame>,
+    fetch: F,
+) -> Fetched<PartialGameList>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<Fetched<DevcadeGame>, Error>>,
+{
+    let mut games = Vec::with_capacity(listed.len());
+    let mut missing = Vec::new();
+    for (index, game) in listed.into_iter().enumerate() {
+        match known.get(&game.id) {
+            Some(known) => games.push((index, known.clone())),
+            None => missing.push((index, game.id)),
+        }
+    }
+
+    let fetched: Vec<_> = futures_util::stream::iter(missing)
+        .map(|(index, id)| {
+            let game = fetch(id.clone());
+            async move { (index, id, game.await) }
+        })
+        .buffer_unordered(MAX_CONCURRENT_FETCHES)
+        .collect()
+        .await;
+
+    let mut stale_since = Vec::new();
+    let mut failed = Vec::new();
+    for (index, game_id, game) in fetched {
+        match game {
+            Ok(game) => {
+                stale_since.push(game.stale_since);
+                games.push((index, game.value));
+            }
+            Err(err) => {
                 log!(
                     Level::Warn,
-                    "Failed to get game by tag {name}: {}",
-                    g.unwrap_err()
+                    "Failed to get game {game_id} by tag {name}: {err}"
                 );
-                None
+                failed.push(GameFetchError {
+                    game_id,
+                    error: err.into(),
+                });
             }
-        })
-        .collect())
+        }
+    }
+    games.sort_by_key(|(index, _)| *index);
+    failed.sort_by(|a, b| a.game_id.cmp(&b.game_id));
+
+    Fetched {
+        value: PartialGameList {
+            games: games.into_iter().map(|(_, game)| game).collect(),
+            failed,
+        },
+        stale_since: oldest(stale_since),
+    }
 }
 
 /**
@@ -588,7 +1590,7 @@ pub async fn tag_games(name: String) -> Result<Vec<DevcadeGame>, Error> {
  * This function will return an error if the server cannot be reached, or if the server returns an
  * error.
  */
-pub async fn user(uid: String) -> Result<User, Error> {
+pub async fn user(uid: String) -> Result<Fetched<User>, Error> {
     network::request_json(format!("{}/{}", api_url(), route::user(uid.as_str())).as_str()).await
 }
 
@@ -602,151 +1604,127 @@ pub async fn user(uid: String) -> Result<User, Error> {
 fn game_from_path(path: &Path) -> Result<DevcadeGame, Error> {
     log!(Level::Trace, "Reading game from path {:?}", path);
     if !path.exists() {
-        return Err(anyhow!("Path does not exist"));
+        return Err(error(ErrorKind::NotFound, "Path does not exist"));
     }
     if path.is_dir() {
-        return Err(anyhow!("Path is a directory"));
+        return Err(error(ErrorKind::NotFound, "Path is a directory"));
     }
     let str = std::fs::read_to_string(path)?;
 
-    let game: DevcadeGame = serde_json::from_str(&str)?;
+    let game: DevcadeGame = serde_json::from_str(&str).map_err(|err| {
+        ResponseError::new(ErrorKind::Io, format!("Couldn't parse game file {path:?}"))
+            .with_details(err)
+    })?;
 
     Ok(game)
 }
 
-async fn game_from_minimal(game: MinimalGame) -> Result<DevcadeGame, Error> {
-    network::request_json::<DevcadeGame>(
-        format!("{}/{}", api_url(), route::game(game.id.as_str())).as_str(),
-    )
-    .await
-}
-
 pub fn current_game() -> DevcadeGame {
     CURRENT_GAME.lock().unwrap().get_mut().clone()
 }
 
-// currently saves to the devcade machine (or local machine if running locally) in the future,
-// should ideally use a remote database / something else.
-pub async fn persistence_save(group: &str, key: &str, value: &str) -> Result<(), anyhow::Error> {
-    log::trace!("saving data to {}/{} ({})", group, key, value);
-    let (path, group) = from_group(group);
-    let full_key = format!("{}/{}", path, group);
-
-    let mut data = DB.lock().await;
-    let mut mod_list = DB_MODIFIED.lock().await;
-
-    let inner = get_submap_or_load(&mut data, full_key.clone()).await?;
-
-    inner.insert(key.to_string(), value.to_string());
-    mod_list.insert(full_key);
-
-    Ok(())
-}
-
-/**
- * Load a value from using a group and key
- * group will start with a game_id, but can be further subdivided by the game to
- * */
-pub async fn persistence_load(group: &str, key: &str) -> Result<String, anyhow::Error> {
-    log::trace!("loading data from {}/{}", group, key);
-    let (path, file_name) = from_group(group);
-    let full_key = format!("{}/{}", path, file_name);
-
-    let mut data = DB.lock().await;
-
-    let inner = get_submap_or_load(&mut data, full_key.clone()).await?;
-
-    inner
-        .get(&key.to_string())
-        .ok_or_else(|| anyhow!("Could not find key {} in group {}", key, full_key))
-        .cloned()
-}
-
-/**
- * Flush all pending writes to the filesystem.
- * */
-pub async fn persistence_flush() -> Result<(), anyhow::Error> {
-    let mut data = DB.lock().await;
-    let mut mod_list = DB_MODIFIED.lock().await;
-
-    log::debug!(
-        "Flushing data in db to file ({} modified groups)",
-        mod_list.len()
-    );
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listed(ids: &[&str]) -> Vec<MinimalGame> {
+        ids.iter()
+            .map(|id| MinimalGame {
+                id: id.to_string(),
+                ..Default::default()
+            })
+            .collect()
+    }
 
-    for key in mod_list.iter() {
-        let inner = get_submap_or_load(&mut data, key.clone()).await?;
-        let file_name = format!("{}.save", key);
-        log::debug!("Flushing to {}", file_name);
-        let path = Path::new(&file_name);
-        let dir = path.parent().expect("path failed to have parents");
-        if !dir.exists() {
-            fs::create_dir_all(dir).await?;
+    fn game(id: &str, name: &str) -> DevcadeGame {
+        DevcadeGame {
+            id: id.to_string(),
+            name: name.to_string(),
+            ..Default::default()
         }
-        fs::write(path, serde_json::to_string(inner)?.as_bytes()).await?;
     }
 
-    mod_list.clear();
-
-    Ok(())
-}
-
-/**
- * Flushes all DB changes, and clears the in-memory cache. This shouldn't need to be done often but
- * can be done if some games are storing too much data and we need to save memory. I don't see this
- * actually needing use unless someone is maliciously (or stupidly) trying to store GBs of data at
- * a time.
- * */
-pub async fn clear_db() -> Result<(), anyhow::Error> {
-    log::info!("Flushing and clearing DB cache");
-    persistence_flush().await?;
-
-    let mut data = DB.lock().await;
-    data.clear();
-
-    Ok(())
-}
-
-fn from_group(group: &str) -> (String, String) {
-    let save_path = Path::new(if *ON_MACHINE {
-        "/home/devcade/.save"
-    } else {
-        "./.save"
-    });
+    fn ids(list: &PartialGameList) -> Vec<&str> {
+        list.games.iter().map(|game| game.id.as_str()).collect()
+    }
 
-    let mut parts: Vec<String> = group.split('/').map(|a| a.to_string()).collect();
-    let group = parts.pop().unwrap_or_default();
-    let save_path = save_path.join(parts.join("/"));
-    (save_path.to_str().unwrap_or("").to_string(), group)
-}
+    #[tokio::test]
+    async fn tag_games_keep_the_listed_order() {
+        let known = HashMap::from([
+            (String::from("a"), game("a", "known")),
+            (String::from("d"), game("d", "known")),
+        ]);
+        let fetched = Mutex::new(Vec::new());
+        let resolved =
+            resolve_tag_games("Arcade", listed(&["e", "a", "b", "d", "c"]), &known, |id| {
+                fetched.lock().unwrap().push(id.clone());
+                async move {
+                    // Games listed earlier take longer, so they are fetched last
+                    let delay = match id.as_str() {
+                        "e" => 30,
+                        "b" => 15,
+                        _ => 0,
+                    };
+                    tokio::time::sleep(Duration::from_millis(delay)).await;
+                    Ok(Fetched {
+                        value: game(&id, "fetched"),
+                        stale_since: None,
+                    })
+                }
+            })
+            .await;
+
+        assert_eq!(ids(&resolved.value), ["e", "a", "b", "d", "c"]);
+        assert_eq!(resolved.value.games[1].name, "known");
+        assert_eq!(resolved.value.games[2].name, "fetched");
+        assert!(resolved.value.failed.is_empty());
+        assert_eq!(resolved.stale_since, None);
+        // Games from the game list are never fetched again
+        let mut fetched = fetched.into_inner().unwrap();
+        fetched.sort();
+        assert_eq!(fetched, ["b", "c", "e"]);
+    }
 
-/**
- * Gets the sub-map at a specified path, and returns the cached version, the version on the
- * filesystem, or a new empty HashMap, in order of preference.
- * */
-async fn get_submap_or_load(
-    db: &mut HashMap<String, HashMap<String, String>>,
-    group: String,
-) -> Result<&mut HashMap<String, String>, anyhow::Error> {
-    let file_name = format!("{}.save", group);
-    if !db.contains_key(&group) {
-        if Path::new(&file_name).exists() {
-            let map = serde_json::from_str::<HashMap<String, String>>(
-                fs::read_to_string(file_name).await?.as_str(),
-            )?;
-            db.insert(group.clone(), map);
-        } else {
-            db.insert(group.clone(), HashMap::new());
-        }
+    #[tokio::test]
+    async fn tag_games_report_what_could_not_be_fetched() {
+        let known = HashMap::from([(String::from("b"), game("b", "known"))]);
+        let resolved = resolve_tag_games(
+            "Arcade",
+            listed(&["d", "b", "c", "a"]),
+            &known,
+            |id| async move {
+                match id.as_str() {
+                    "c" => Ok(Fetched {
+                        value: game(&id, "cached"),
+                        stale_since: Some(1234),
+                    }),
+                    "a" => Err(error(ErrorKind::NotFound, "Devcade API couldn't find it")),
+                    _ => Err(error(
+                        ErrorKind::ApiOffline,
+                        "Couldn't reach the Devcade API",
+                    )),
+                }
+            },
+        )
+        .await;
+
+        assert_eq!(ids(&resolved.value), ["b", "c"]);
+        let failed: Vec<(&str, ErrorKind)> = resolved
+            .value
+            .failed
+            .iter()
+            .map(|failed| (failed.game_id.as_str(), failed.error.kind))
+            .collect();
+        assert_eq!(
+            failed,
+            [("a", ErrorKind::NotFound), ("d", ErrorKind::ApiOffline)]
+        );
+        assert_eq!(resolved.stale_since, Some(1234));
     }
-    Ok(db.get_mut(&group).unwrap())
-}
 
-/**
- * Gets the total number of K, V pairs across the entire cache, as a rough proxy for how large the
- * current cache is.
- * */
-pub async fn db_cache_size() -> usize {
-    let data = DB.lock().await;
-    data.values().map(|hm| hm.len()).sum()
+    #[test]
+    fn the_oldest_stale_value_wins() {
+        assert_eq!(oldest([None, None]), None);
+        assert_eq!(oldest([None, Some(20), Some(10)]), Some(10));
+    }
 }
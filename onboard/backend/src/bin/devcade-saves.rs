@@ -0,0 +1,163 @@
+//! Export and import save data through a running backend.
+//!
+//! ```text
+//! devcade-saves export [--game GAME_ID] FILE
+//! devcade-saves import [--replace [--force]] FILE
+//! ```
+//!
+//! Exports write every game's save data (or only `GAME_ID`'s) to `FILE` as a save archive.
+//! Imports merge the archive in `FILE` into the cabinet's save data, or with `--replace` remove the
+//! save data of the games in the archive first. Replacing every game's save data with an empty
+//! archive also needs `--force`.
+
+use anyhow::{anyhow, bail, Error};
+use backend::persistence::archive::check_archive;
+use backend::servers::path::onboard_pipe;
+use devcade_onboard_types::{
+    Capability, Hello, ImportMode, Request, RequestBody, Response, ResponseBody, SaveArchive,
+    PROTOCOL_VERSION,
+};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines, ReadHalf, WriteHalf};
+use tokio::net::UnixStream;
+
+const USAGE: &str = "Usage:
+    devcade-saves export [--game GAME_ID] FILE
+    devcade-saves import [--replace [--force]] FILE";
+
+/**
+ * A connection to the backend's onboard socket
+ */
+struct Client {
+    lines: Lines<BufReader<ReadHalf<UnixStream>>>,
+    writer: WriteHalf<UnixStream>,
+    next_id: u32,
+}
+
+impl Client {
+    /**
+     * Connect to the backend and agree on a protocol version
+     */
+    async fn connect(path: &str) -> Result<Self, Error> {
+        let stream = UnixStream::connect(path)
+            .await
+            .map_err(|err| anyhow!("Couldn't connect to the backend at {path}: {err}"))?;
+        let (reader, writer) = tokio::io::split(stream);
+        let mut client = Client {
+            lines: BufReader::new(reader).lines(),
+            writer,
+            next_id: 0,
+        };
+
+        let hello = Hello {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: vec![Capability::Admin],
+        };
+        match client.send(RequestBody::Hello(hello)).await? {
+            ResponseBody::Welcome(_) => Ok(client),
+            body => bail!("Backend refused the connection: {body}"),
+        }
+    }
+
+    /**
+     * Send a request and wait for its response. Errors from the backend are returned as errors.
+     */
+    async fn send(&mut self, body: RequestBody) -> Result<ResponseBody, Error> {
+        self.next_id += 1;
+        let request = Request {
+            request_id: self.next_id,
+            body,
+        };
+        let mut message = serde_json::to_vec(&request)?;
+        message.push(b'\n');
+        self.writer.write_all(&message).await?;
+
+        while let Some(line) = self.lines.next_line().await? {
+            let response: Response = serde_json::from_str(&line)?;
+            if response.request_id != request.request_id {
+                continue;
+            }
+            return match response.body {
+                ResponseBody::Err(err) => Err(err.into()),
+                body => Ok(body),
+            };
+        }
+        bail!("Backend closed the connection")
+    }
+}
+
+async fn export(client: &mut Client, game_id: Option<String>, file: &str) -> Result<(), Error> {
+    let ResponseBody::SaveArchive(archive) = client.send(RequestBody::ExportSaves(game_id)).await?
+    else {
+        bail!("Unexpected response to export");
+    };
+    let contents = serde_json::to_vec_pretty(&archive)?;
+    tokio::fs::write(file, contents)
+        .await
+        .map_err(|err| anyhow!("Couldn't write {file}: {err}"))?;
+    println!(
+        "Exported {} groups ({} keys) to {file}",
+        archive.manifest.groups, archive.manifest.keys
+    );
+    Ok(())
+}
+
+async fn import(client: &mut Client, mode: ImportMode, file: &str) -> Result<(), Error> {
+    let contents = tokio::fs::read(file)
+        .await
+        .map_err(|err| anyhow!("Couldn't read {file}: {err}"))?;
+    let archive: SaveArchive = serde_json::from_slice(&contents)
+        .map_err(|err| anyhow!("{file} isn't a valid save archive: {err}"))?;
+    // The backend checks this too, but catching it here gives a better message
+    check_archive(&archive).map_err(|err| anyhow!("{file} isn't a valid save archive: {err}"))?;
+
+    let ResponseBody::Imported(summary) =
+        client.send(RequestBody::ImportSaves(archive, mode)).await?
+    else {
+        bail!("Unexpected response to import");
+    };
+    println!(
+        "Imported {} groups ({} keys) from {file}, removed {} existing groups",
+        summary.groups, summary.keys, summary.removed_groups
+    );
+    Ok(())
+}
+
+async fn run(args: &[String]) -> Result<(), Error> {
+    let usage = || anyhow!("{USAGE}");
+    let (command, args) = args.split_first().ok_or_else(usage)?;
+    match (command.as_str(), args) {
+        ("export", [file]) => {
+            let mut client = Client::connect(&onboard_pipe()).await?;
+            export(&mut client, None, file).await
+        }
+        ("export", [flag, game_id, file]) if flag == "--game" => {
+            let mut client = Client::connect(&onboard_pipe()).await?;
+            export(&mut client, Some(game_id.clone()), file).await
+        }
+        ("import", [file]) => {
+            let mut client = Client::connect(&onboard_pipe()).await?;
+            import(&mut client, ImportMode::Merge, file).await
+        }
+        ("import", [flag, file]) if flag == "--replace" => {
+            let mut client = Client::connect(&onboard_pipe()).await?;
+            import(&mut client, ImportMode::Replace, file).await
+        }
+        ("import", [flag, force, file]) if flag == "--replace" && force == "--force" => {
+            let mut client = Client::connect(&onboard_pipe()).await?;
+            import(&mut client, ImportMode::ForceReplace, file).await
+        }
+        _ => Err(usage()),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    // Same as the backend, so the socket is found in the same place
+    let _ = dotenvy::from_filename("../.env");
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Err(err) = run(&args).await {
+        eprintln!("{err}");
+        std::process::exit(1);
+    }
+}
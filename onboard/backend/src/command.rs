@@ -1,19 +1,40 @@
 use crate::api::{self, nfc_user};
+use crate::persistence::{self, game_group, PLAYER_SAVE_DIR};
+use crate::{achievements, leaderboard, stats, supervisor};
 
 use crate::api::{
     download_banner, download_game, download_icon, game_list, game_list_from_fs, launch_game,
-    nfc_tags, persistence_flush, persistence_load, persistence_save, tag_games, tag_list, user,
+    nfc_tags, tag, tag_from_fs, tag_games, tag_games_from_fs, tag_list, tag_list_from_fs, user,
+    user_from_fs, Fetched, ProgressSender,
 };
-use devcade_onboard_types::{RequestBody, ResponseBody};
+use devcade_onboard_types::error::{ErrorKind, ResponseError};
+use devcade_onboard_types::{PartialGameList, RequestBody, ResponseBody, StaleResponse, Value};
 
 /**
- * Handle a request from the frontend.
+ * Handle a request from the frontend. Long running requests report their progress through
+ * `progress` if it is given.
  */
-pub async fn handle(req: RequestBody) -> ResponseBody {
+pub async fn handle(req: RequestBody, progress: Option<ProgressSender>) -> ResponseBody {
     match req {
         RequestBody::Ping => ResponseBody::Pong,
+        // The handshake only means something for a connection, so the socket servers answer it
+        RequestBody::Hello(_) => ResponseError::new(
+            ErrorKind::InvalidRequest,
+            "Hello must be sent directly to a socket server",
+        )
+        .into(),
+        RequestBody::Cancel(_) => ResponseError::new(
+            ErrorKind::InvalidRequest,
+            "Cancel must be sent directly to a socket server",
+        )
+        .into(),
+        RequestBody::Subscribe | RequestBody::Unsubscribe => ResponseError::new(
+            ErrorKind::InvalidRequest,
+            "Subscriptions must be sent directly to a socket server",
+        )
+        .into(),
         RequestBody::GetGameList => match game_list().await {
-            Ok(games) => ResponseBody::GameList(games),
+            Ok(games) => fetched(games, ResponseBody::GameList),
             Err(_) => match game_list_from_fs() {
                 Ok(games) => ResponseBody::GameList(games),
                 Err(err) => err.into(),
@@ -24,13 +45,20 @@ pub async fn handle(req: RequestBody) -> ResponseBody {
             Err(err) => err.into(),
         },
         RequestBody::GetGame(game_id) => match game_list().await {
-            Ok(game) => match game.into_iter().find(|g| g.id == game_id) {
-                Some(game) => ResponseBody::Game(game),
-                None => ResponseBody::Err(format!("Game with ID {game_id} not found")),
+            Ok(games) => match games.value.iter().position(|g| g.id == game_id) {
+                Some(index) => fetched(
+                    games.map(|mut games| games.swap_remove(index)),
+                    ResponseBody::Game,
+                ),
+                None => ResponseError::new(
+                    ErrorKind::NotFound,
+                    format!("Game with ID {game_id} not found"),
+                )
+                .into(),
             },
             Err(err) => err.into(),
         },
-        RequestBody::DownloadGame(game_id) => match download_game(game_id).await {
+        RequestBody::DownloadGame(game_id) => match download_game(game_id, progress).await {
             Ok(_) => ResponseBody::Ok,
             Err(err) => err.into(),
         },
@@ -46,29 +74,51 @@ pub async fn handle(req: RequestBody) -> ResponseBody {
             Ok(_) => ResponseBody::Ok,
             Err(err) => err.into(),
         },
+        RequestBody::KillGame => match supervisor::kill() {
+            Ok(()) => ResponseBody::Ok,
+            Err(err) => err.into(),
+        },
+        RequestBody::GetRunningGame => ResponseBody::RunningGame(supervisor::running_game()),
+        RequestBody::GetPlayStats(game_id) => match stats::play_stats(game_id).await {
+            Ok(stats) => ResponseBody::PlayStats(stats),
+            Err(err) => err.into(),
+        },
+        RequestBody::GetPopularGames(window_secs, limit) => {
+            match stats::popular_games(window_secs, limit).await {
+                Ok(games) => ResponseBody::PopularGames(games),
+                Err(err) => err.into(),
+            }
+        }
         RequestBody::SetProduction(prod) => {
             crate::env::set_production(prod);
             ResponseBody::Ok
         }
-        RequestBody::GetTagList => match tag_list().await {
-            Ok(tags) => ResponseBody::TagList(tags),
-            Err(err) => err.into(),
-        },
-        RequestBody::GetTag(tag_name) => match tag_list().await {
-            Ok(tags) => match tags.into_iter().find(|t| t.name == tag_name) {
-                Some(tag) => ResponseBody::Tag(tag),
-                None => ResponseBody::Err(format!("Tag with name {tag_name} not found")),
+        RequestBody::GetTagList => {
+            api_or_installed(tag_list().await, tag_list_from_fs, ResponseBody::TagList)
+        }
+        RequestBody::GetTag(tag_name) => api_or_installed(
+            tag(tag_name.clone()).await,
+            || tag_from_fs(&tag_name),
+            ResponseBody::Tag,
+        ),
+        RequestBody::GetGameListFromTag(tag_name) => api_or_installed(
+            tag_games(tag_name.clone()).await,
+            || {
+                Ok(PartialGameList {
+                    games: tag_games_from_fs(&tag_name)?,
+                    failed: Vec::new(),
+                })
             },
-            Err(err) => err.into(),
-        },
-        RequestBody::GetGameListFromTag(tag_name) => match tag_games(tag_name).await {
-            Ok(games) => ResponseBody::GameList(games),
-            Err(err) => err.into(),
-        },
-        RequestBody::GetUser(uid) => match user(uid).await {
-            Ok(user) => ResponseBody::User(user),
-            Err(err) => err.into(),
-        },
+            |list| match list.failed.is_empty() {
+                true => ResponseBody::GameList(list.games),
+                false => ResponseBody::PartialGameList(list),
+            },
+        ),
+        RequestBody::GetUser(uid) => api_or_installed(
+            user(uid.clone()).await,
+            || user_from_fs(&uid),
+            ResponseBody::User,
+        ),
         RequestBody::GetNfcTag(reader_id) => match nfc_tags(reader_id).await {
             Ok(association_id) => ResponseBody::NfcTag(association_id),
             Err(err) => err.into(),
@@ -78,22 +128,453 @@ pub async fn handle(req: RequestBody) -> ResponseBody {
             Err(err) => err.into(),
         },
         RequestBody::Save(group, key, value) => {
-            let group = format!("{}/{}", api::current_game().id, group);
-            match persistence_save(group.as_str(), key.as_str(), value.as_str()).await {
+            let saved = async {
+                let group = game_group(&api::current_game().id, &group)?;
+                persistence::save(&group, &key, value).await
+            };
+            match saved.await {
                 Ok(()) => ResponseBody::Ok,
                 Err(err) => err.into(),
             }
         }
         RequestBody::Load(group, key) => {
-            let group = format!("{}/{}", api::current_game().id, group);
-            match persistence_load(group.as_str(), key.as_str()).await {
-                Ok(s) => ResponseBody::Object(s),
+            let loaded = async {
+                let group = game_group(&api::current_game().id, &group)?;
+                persistence::load(&group, &key).await
+            };
+            match loaded.await {
+                Ok(value) => ResponseBody::Object(legacy_object(value)),
+                Err(err) => err.into(),
+            }
+        }
+        RequestBody::SaveValue(group, key, value) => {
+            let saved = async {
+                let group = game_group(&api::current_game().id, &group)?;
+                persistence::save(&group, &key, value).await
+            };
+            match saved.await {
+                Ok(()) => ResponseBody::Ok,
+                Err(err) => err.into(),
+            }
+        }
+        RequestBody::LoadValue(group, key) => {
+            let loaded = async {
+                let group = game_group(&api::current_game().id, &group)?;
+                persistence::load(&group, &key).await
+            };
+            match loaded.await {
+                Ok(value) => ResponseBody::Value(value),
+                Err(err) => err.into(),
+            }
+        }
+        RequestBody::SaveBytes(group, key, value) => {
+            let saved = async {
+                let group = game_group(&api::current_game().id, &group)?;
+                persistence::save(&group, &key, value.to_base64()).await
+            };
+            match saved.await {
+                Ok(()) => ResponseBody::Ok,
+                Err(err) => err.into(),
+            }
+        }
+        RequestBody::LoadBytes(group, key) => {
+            let loaded = async {
+                let group = game_group(&api::current_game().id, &group)?;
+                persistence::load_bytes(&group, &key).await
+            };
+            match loaded.await {
+                Ok(value) => ResponseBody::Bytes(value),
+                Err(err) => err.into(),
+            }
+        }
+        RequestBody::Increment(group, key, amount) => {
+            let incremented = async {
+                let group = game_group(&api::current_game().id, &group)?;
+                persistence::increment(&group, &key, amount).await
+            };
+            match incremented.await {
+                Ok(value) => ResponseBody::Value(value),
+                Err(err) => err.into(),
+            }
+        }
+        RequestBody::CompareAndSwap(group, key, expected, new) => {
+            let swapped = async {
+                let group = game_group(&api::current_game().id, &group)?;
+                persistence::compare_and_swap(&group, &key, expected, new).await
+            };
+            match swapped.await {
+                Ok(result) => ResponseBody::Swapped(result),
+                Err(err) => err.into(),
+            }
+        }
+        RequestBody::GetStorageUsage => match persistence::usage().await {
+            Ok(usage) => ResponseBody::StorageUsage(usage),
+            Err(err) => err.into(),
+        },
+        RequestBody::ExportSaves(game_id) => match persistence::export(game_id.as_deref()).await {
+            Ok(archive) => ResponseBody::SaveArchive(archive),
+            Err(err) => err.into(),
+        },
+        RequestBody::ImportSaves(archive, mode) => {
+            match persistence::import(&archive, mode).await {
+                Ok(summary) => ResponseBody::Imported(summary),
+                Err(err) => err.into(),
+            }
+        }
+        RequestBody::GetGameLeaderboards(game_id, limit) => {
+            match leaderboard::leaderboards(&game_id, limit).await {
+                Ok(boards) => ResponseBody::Leaderboards(boards),
+                Err(err) => err.into(),
+            }
+        }
+        RequestBody::SubmitScore(board, score, metadata) => {
+            let game_id = api::current_game().id;
+            match leaderboard::submit(&game_id, &board, score, metadata, None).await {
+                Ok(rank) => ResponseBody::Rank(rank),
+                Err(err) => err.into(),
+            }
+        }
+        RequestBody::SubmitPlayerScore(player, board, score, metadata) => {
+            let submitted = async {
+                check_player(&player)?;
+                let game_id = api::current_game().id;
+                leaderboard::submit(&game_id, &board, score, metadata, Some(player)).await
+            };
+            match submitted.await {
+                Ok(rank) => ResponseBody::Rank(rank),
+                Err(err) => err.into(),
+            }
+        }
+        RequestBody::GetLeaderboard(board, limit) => {
+            let game_id = api::current_game().id;
+            match leaderboard::leaderboard(&game_id, &board, limit).await {
+                Ok(board) => ResponseBody::Leaderboard(board),
+                Err(err) => err.into(),
+            }
+        }
+        RequestBody::GetAchievements(game_id) => match achievements::achievements(&game_id).await {
+            Ok(achievements) => ResponseBody::Achievements(achievements),
+            Err(err) => err.into(),
+        },
+        RequestBody::DefineAchievements(defined) => {
+            let game_id = api::current_game().id;
+            match achievements::define(&game_id, defined).await {
+                Ok(()) => ResponseBody::Ok,
+                Err(err) => err.into(),
+            }
+        }
+        RequestBody::UnlockAchievement(id) => {
+            let game_id = api::current_game().id;
+            match achievements::unlock(&game_id, &id, None).await {
+                Ok(unlocked) => ResponseBody::Unlocked(unlocked),
+                Err(err) => err.into(),
+            }
+        }
+        RequestBody::UnlockPlayerAchievement(player, id) => {
+            let unlocked = async {
+                check_player(&player)?;
+                let game_id = api::current_game().id;
+                achievements::unlock(&game_id, &id, Some(player)).await
+            };
+            match unlocked.await {
+                Ok(unlocked) => ResponseBody::Unlocked(unlocked),
+                Err(err) => err.into(),
+            }
+        }
+        RequestBody::Delete(group, key) => {
+            let deleted = async {
+                let group = game_group(&api::current_game().id, &group)?;
+                persistence::delete(&group, &key).await
+            };
+            match deleted.await {
+                Ok(()) => ResponseBody::Ok,
+                Err(err) => err.into(),
+            }
+        }
+        RequestBody::ListKeys(group) => {
+            let listed = async {
+                let group = game_group(&api::current_game().id, &group)?;
+                persistence::list_keys(&group).await
+            };
+            match listed.await {
+                Ok(keys) => ResponseBody::Keys(keys),
+                Err(err) => err.into(),
+            }
+        }
+        RequestBody::ListGroups => match persistence::list_groups(&api::current_game().id).await {
+            Ok(groups) => ResponseBody::Groups(groups),
+            Err(err) => err.into(),
+        },
+        RequestBody::ClearGroup(group) => {
+            let cleared = async {
+                let group = game_group(&api::current_game().id, &group)?;
+                persistence::clear_group(&group).await
+            };
+            match cleared.await {
+                Ok(()) => ResponseBody::Ok,
                 Err(err) => err.into(),
             }
         }
-        RequestBody::Flush => match persistence_flush().await {
+        RequestBody::Flush => match persistence::flush().await {
             Ok(()) => ResponseBody::Ok,
             Err(err) => err.into(),
         },
+        RequestBody::SavePlayer(player, group, key, value) => {
+            let saved = async {
+                let group = game_group(&player_dir(&player)?, &group)?;
+                persistence::save(&group, &key, value).await
+            };
+            match saved.await {
+                Ok(()) => ResponseBody::Ok,
+                Err(err) => err.into(),
+            }
+        }
+        RequestBody::LoadPlayer(player, group, key) => {
+            let loaded = async {
+                let group = game_group(&player_dir(&player)?, &group)?;
+                persistence::load(&group, &key).await
+            };
+            match loaded.await {
+                Ok(value) => ResponseBody::Object(legacy_object(value)),
+                Err(err) => err.into(),
+            }
+        }
+        RequestBody::DeletePlayer(player, group, key) => {
+            let deleted = async {
+                let group = game_group(&player_dir(&player)?, &group)?;
+                persistence::delete(&group, &key).await
+            };
+            match deleted.await {
+                Ok(()) => ResponseBody::Ok,
+                Err(err) => err.into(),
+            }
+        }
+        RequestBody::ListPlayerKeys(player, group) => {
+            let listed = async {
+                let group = game_group(&player_dir(&player)?, &group)?;
+                persistence::list_keys(&group).await
+            };
+            match listed.await {
+                Ok(keys) => ResponseBody::Keys(keys),
+                Err(err) => err.into(),
+            }
+        }
+        RequestBody::ListPlayerGroups(player) => {
+            let listed = async { persistence::list_groups(&player_dir(&player)?).await };
+            match listed.await {
+                Ok(groups) => ResponseBody::Groups(groups),
+                Err(err) => err.into(),
+            }
+        }
+        RequestBody::ClearPlayerGroup(player, group) => {
+            let cleared = async {
+                let group = game_group(&player_dir(&player)?, &group)?;
+                persistence::clear_group(&group).await
+            };
+            match cleared.await {
+                Ok(()) => ResponseBody::Ok,
+                Err(err) => err.into(),
+            }
+        }
+    }
+}
+
+/**
+ * Turn a saved value into what `Load` has always answered with. Values saved with `Save` are
+ * strings and come back unchanged, anything else comes back as JSON.
+ */
+fn legacy_object(value: Value) -> String {
+    match value {
+        Value::String(value) => value,
+        value => value.to_string(),
+    }
+}
+
+/**
+ * Build the response for something fetched from the API, marking it stale if it was built from
+ * cached data because the API couldn't be asked
+ */
+fn fetched<T>(fetched: Fetched<T>, body: impl FnOnce(T) -> ResponseBody) -> ResponseBody {
+    let body = body(fetched.value);
+    match fetched.stale_since {
+        Some(fetched_at) => ResponseBody::Stale(StaleResponse {
+            fetched_at,
+            body: Box::new(body),
+        }),
+        None => body,
+    }
+}
+
+/**
+ * Build the response for something requested from the API. If the API couldn't give an answer at
+ * all, the response is built from what is `installed` instead.
+ */
+fn api_or_installed<T>(
+    requested: Result<Fetched<T>, anyhow::Error>,
+    installed: impl FnOnce() -> Result<T, anyhow::Error>,
+    body: impl FnOnce(T) -> ResponseBody,
+) -> ResponseBody {
+    match requested {
+        Ok(requested) => fetched(requested, body),
+        Err(err) if api_unavailable(&err) => match installed() {
+            Ok(value) => body(value),
+            Err(err) => err.into(),
+        },
+        Err(err) => err.into(),
+    }
+}
+
+/**
+ * Whether an error means the API couldn't give an answer at all, as opposed to answering that
+ * something doesn't exist. Only then is it worth falling back to what is installed.
+ */
+fn api_unavailable(err: &anyhow::Error) -> bool {
+    match err.downcast_ref::<ResponseError>() {
+        Some(err) => matches!(err.kind, ErrorKind::ApiOffline | ErrorKind::ApiError),
+        None => true,
+    }
+}
+
+/**
+ * Make sure a player handle belongs to someone who presented their tag to the running game, so a
+ * game can't act on behalf of players who never played it
+ */
+fn check_player(player: &str) -> Result<(), anyhow::Error> {
+    if !supervisor::player_present(player) {
+        return Err(ResponseError::new(
+            ErrorKind::NotFound,
+            format!("Player {player} hasn't presented their tag to this game"),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/**
+ * Get the group prefix a player's save data is stored under for the current game. Games only get
+ * to touch the data of players who presented their tag while the game was running, so a game
+ * can't read someone's progress just by guessing or remembering their handle.
+ */
+fn player_dir(player: &str) -> Result<String, anyhow::Error> {
+    check_player(player)?;
+    Ok(format!(
+        "{}/{}/{}",
+        api::current_game().id,
+        PLAYER_SAVE_DIR,
+        player
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use devcade_onboard_types::schema::{DevcadeGame, Tag, User};
+    use lazy_static::lazy_static;
+    use tempfile::TempDir;
+
+    lazy_static! {
+        // Every test sees the same installed games, so setting DEVCADE_PATH once can't race
+        static ref INSTALLED: TempDir = {
+            let dir = TempDir::new().unwrap();
+            install(&dir, "pong", &["Arcade", "Classic"], "alice");
+            install(&dir, "snake", &["Arcade"], "bob");
+            std::env::set_var("DEVCADE_PATH", dir.path());
+            dir
+        };
+    }
+
+    fn install(dir: &TempDir, id: &str, tags: &[&str], uploader: &str) {
+        let game = DevcadeGame {
+            id: id.to_string(),
+            tags: tags
+                .iter()
+                .map(|name| Tag {
+                    name: name.to_string(),
+                    ..Default::default()
+                })
+                .collect(),
+            user: User {
+                id: uploader.to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let game_dir = dir.path().join(id);
+        std::fs::create_dir_all(&game_dir).unwrap();
+        std::fs::write(
+            game_dir.join("game.json"),
+            serde_json::to_vec(&game).unwrap(),
+        )
+        .unwrap();
+        // Anything else next to the game is skipped
+        std::fs::write(game_dir.join("notes.txt"), "not a game").unwrap();
+    }
+
+    fn api_error(kind: ErrorKind) -> anyhow::Error {
+        ResponseError::new(kind, "API said no").into()
+    }
+
+    fn error_kind(body: ResponseBody) -> ErrorKind {
+        match body {
+            ResponseBody::Err(err) => err.kind,
+            body => panic!("expected an error, got {body}"),
+        }
+    }
+
+    #[test]
+    fn installed_games_answer_for_the_api() {
+        lazy_static::initialize(&INSTALLED);
+
+        let tags: Vec<String> = tag_list_from_fs()
+            .unwrap()
+            .into_iter()
+            .map(|tag| tag.name)
+            .collect();
+        assert_eq!(tags, ["Arcade", "Classic"]);
+        assert_eq!(tag_from_fs("Classic").unwrap().name, "Classic");
+        assert_eq!(
+            error_kind(tag_from_fs("Puzzle").unwrap_err().into()),
+            ErrorKind::NotFound
+        );
+
+        let mut games: Vec<String> = tag_games_from_fs("Arcade")
+            .unwrap()
+            .into_iter()
+            .map(|game| game.id)
+            .collect();
+        games.sort();
+        assert_eq!(games, ["pong", "snake"]);
+        assert!(tag_games_from_fs("Puzzle").unwrap().is_empty());
+
+        assert_eq!(user_from_fs("bob").unwrap().id, "bob");
+        assert_eq!(
+            error_kind(user_from_fs("carol").unwrap_err().into()),
+            ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn only_unreachable_apis_fall_back() {
+        lazy_static::initialize(&INSTALLED);
+
+        assert!(api_unavailable(&api_error(ErrorKind::ApiOffline)));
+        assert!(api_unavailable(&api_error(ErrorKind::ApiError)));
+        assert!(api_unavailable(&anyhow::anyhow!("connection reset")));
+        assert!(!api_unavailable(&api_error(ErrorKind::NotFound)));
+
+        let offline = api_or_installed(
+            Err(api_error(ErrorKind::ApiOffline)),
+            || tag_from_fs("Classic"),
+            ResponseBody::Tag,
+        );
+        assert!(matches!(offline, ResponseBody::Tag(tag) if tag.name == "Classic"));
+
+        // The API knows best what exists, even if an installed game still has the tag
+        let not_found = api_or_installed(
+            Err(api_error(ErrorKind::NotFound)),
+            || -> Result<Tag, anyhow::Error> { panic!("fell back on an answer from the API") },
+            ResponseBody::Tag,
+        );
+        assert_eq!(error_kind(not_found), ErrorKind::NotFound);
     }
 }
@@ -0,0 +1,37 @@
+use devcade_onboard_types::Event;
+use lazy_static::lazy_static;
+use tokio::sync::broadcast;
+
+// Events that aren't picked up by a subscriber within this many newer events are dropped for that
+// subscriber. Events are small and rare, so this is plenty.
+const EVENT_BUFFER: usize = 64;
+
+lazy_static! {
+    static ref EVENTS: broadcast::Sender<Event> = broadcast::channel(EVENT_BUFFER).0;
+}
+
+/**
+ * Push an event to every subscribed client. Events published while nobody is subscribed are
+ * silently dropped.
+ */
+pub fn publish(event: Event) {
+    log::debug!("Publishing event: {event}");
+    // Only fails if there are no subscribers, which is fine
+    let _ = EVENTS.send(event);
+}
+
+/**
+ * Get a receiver for all events published from now on.
+ */
+#[must_use]
+pub fn subscribe() -> broadcast::Receiver<Event> {
+    EVENTS.subscribe()
+}
+
+/**
+ * Whether any client is subscribed to events right now
+ */
+#[must_use]
+pub fn has_subscribers() -> bool {
+    EVENTS.receiver_count() > 0
+}
@@ -0,0 +1,181 @@
+use crate::persistence::{self, game_group, names};
+use crate::stats::unix_secs;
+use anyhow::Error;
+use devcade_onboard_types::error::{ErrorKind, ResponseError};
+use devcade_onboard_types::{Leaderboard, Score, Value};
+use std::time::SystemTime;
+
+/**
+ * Directory inside a game's save directory that its leaderboards are kept in, one group per board.
+ * Like player save data, it is hidden from the game's shared groups.
+ */
+pub const LEADERBOARD_DIR: &str = ".leaderboards";
+
+/**
+ * Number of scores kept on each board. Lower scores fall off the bottom.
+ */
+pub const MAX_SCORES: usize = 100;
+
+// Every board is a single value, so a new score is ranked against the whole board atomically
+const SCORES_KEY: &str = "scores";
+
+/**
+ * Name of the group a board is stored in
+ */
+fn board_group(game_id: &str, board: &str) -> Result<String, Error> {
+    names::check_game_id(game_id)?;
+    game_group(&format!("{game_id}/{LEADERBOARD_DIR}"), board)
+}
+
+/**
+ * Load the scores on a board and the value they were stored as, which is `None` for a board
+ * nobody has submitted to yet
+ */
+async fn load_scores(group: &str) -> Result<(Vec<Score>, Option<Value>), Error> {
+    match persistence::load(group, SCORES_KEY).await {
+        Ok(value) => {
+            let scores = serde_json::from_value(value.clone())?;
+            Ok((scores, Some(value)))
+        }
+        Err(err) => match err.downcast_ref::<ResponseError>() {
+            Some(ResponseError {
+                kind: ErrorKind::KeyNotFound,
+                ..
+            }) => Ok((Vec::new(), None)),
+            _ => Err(err),
+        },
+    }
+}
+
+/**
+ * Put a score in its place on a board, below any equal scores that were there first. Returns the
+ * score's rank (starting at 1), or `None` if it is too low to be kept.
+ */
+fn insert_score(scores: &mut Vec<Score>, score: Score) -> Option<u32> {
+    let index = scores
+        .iter()
+        .position(|other| other.score < score.score)
+        .unwrap_or(scores.len());
+    if index >= MAX_SCORES {
+        return None;
+    }
+    scores.insert(index, score);
+    scores.truncate(MAX_SCORES);
+    Some(index as u32 + 1)
+}
+
+/**
+ * Submit a score to one of a game's boards, creating the board if it doesn't exist yet. Returns
+ * the score's rank on the board (starting at 1), or `None` if it didn't make the board.
+ *
+ * # Errors
+ * This function will return an error if the board name isn't a valid save group, or if the board
+ * can't be loaded or saved.
+ */
+pub async fn submit(
+    game_id: &str,
+    board: &str,
+    score: i64,
+    metadata: Value,
+    player: Option<String>,
+) -> Result<Option<u32>, Error> {
+    let group = board_group(game_id, board)?;
+    let score = Score {
+        score,
+        player,
+        metadata,
+        submitted_at: unix_secs(SystemTime::now()),
+    };
+
+    // Another submission may land between loading the board and saving it, in which case the new
+    // board is ranked against that one instead
+    loop {
+        let (mut scores, current) = load_scores(&group).await?;
+        let Some(rank) = insert_score(&mut scores, score.clone()) else {
+            return Ok(None);
+        };
+        let swap = persistence::compare_and_swap(
+            &group,
+            SCORES_KEY,
+            current,
+            serde_json::to_value(scores)?,
+        )
+        .await?;
+        if swap.swapped {
+            return Ok(Some(rank));
+        }
+    }
+}
+
+/**
+ * Get the top `limit` scores on one of a game's boards. A board nobody has submitted to is empty.
+ *
+ * # Errors
+ * This function will return an error if the board name isn't a valid save group, or if the board
+ * can't be loaded.
+ */
+pub async fn leaderboard(game_id: &str, board: &str, limit: u32) -> Result<Leaderboard, Error> {
+    let group = board_group(game_id, board)?;
+    let (mut scores, _) = load_scores(&group).await?;
+    scores.truncate(limit as usize);
+    Ok(Leaderboard {
+        board: board.to_string(),
+        scores,
+    })
+}
+
+/**
+ * Get the top `limit` scores on every board of a game, sorted by board name
+ *
+ * # Errors
+ * This function will return an error if the game ID isn't valid, or if a board can't be loaded.
+ */
+pub async fn leaderboards(game_id: &str, limit: u32) -> Result<Vec<Leaderboard>, Error> {
+    names::check_game_id(game_id)?;
+    let boards = persistence::list_groups(&format!("{game_id}/{LEADERBOARD_DIR}")).await?;
+
+    let mut leaderboards = Vec::with_capacity(boards.len());
+    for board in boards {
+        leaderboards.push(leaderboard(game_id, &board, limit).await?);
+    }
+    Ok(leaderboards)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn score(score: i64) -> Score {
+        Score {
+            score,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn scores_are_ranked_best_first() {
+        let mut scores = Vec::new();
+        assert_eq!(insert_score(&mut scores, score(10)), Some(1));
+        assert_eq!(insert_score(&mut scores, score(30)), Some(1));
+        assert_eq!(insert_score(&mut scores, score(20)), Some(2));
+        // Ties go to whoever got there first
+        let mut tie = score(20);
+        tie.metadata = Value::from("second");
+        assert_eq!(insert_score(&mut scores, tie), Some(3));
+
+        let ranked: Vec<i64> = scores.iter().map(|score| score.score).collect();
+        assert_eq!(ranked, [30, 20, 20, 10]);
+        assert_eq!(scores[2].metadata, "second");
+    }
+
+    #[test]
+    fn full_boards_drop_the_lowest_score() {
+        let mut scores: Vec<Score> = (0..MAX_SCORES as i64).rev().map(score).collect();
+
+        assert_eq!(insert_score(&mut scores, score(-1)), None);
+        assert_eq!(insert_score(&mut scores, score(0)), None);
+        assert_eq!(insert_score(&mut scores, score(50)), Some(51));
+        assert_eq!(scores.len(), MAX_SCORES);
+        assert_eq!(scores.last().unwrap().score, 1);
+    }
+}
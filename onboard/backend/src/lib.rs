@@ -21,6 +21,41 @@ pub mod command;
  */
 pub mod nfc;
 
+/**
+ * Module for pushing unsolicited events to subscribed clients
+ */
+pub mod events;
+
+/**
+ * Module for running the game process and keeping track of it
+ */
+pub mod supervisor;
+
+/**
+ * Module for stopping games nobody is playing anymore
+ */
+pub mod watchdog;
+
+/**
+ * Module for recording play sessions and computing play time statistics
+ */
+pub mod stats;
+
+/**
+ * Module for storing and loading game save data
+ */
+pub mod persistence;
+
+/**
+ * Module for keeping each game's high scores
+ */
+pub mod leaderboard;
+
+/**
+ * Module for the achievements games define and unlock
+ */
+pub mod achievements;
+
 /**
  * Module for safely getting environment variables, logging any errors that occur and providing
  * default values.
@@ -29,7 +64,9 @@ pub mod env {
     // TODO Cache env vars? Probably not necessary
     use log::{log, Level};
     use std::env;
+    use std::path::{Path, PathBuf};
     use std::sync::Mutex;
+    use std::time::Duration;
 
     // TODO should be Mutex? Lmao
     static PRODUCTION: Mutex<bool> = Mutex::new(true);
@@ -82,6 +119,166 @@ pub mod env {
         }
     }
 
+    /**
+     * Get how long a game may go without any input before it is stopped. This is read from
+     * DEVCADE_IDLE_TIMEOUT in minutes, where 0 disables the timeout. If the value is not set or
+     * invalid, it will default to 10 minutes.
+     */
+    #[must_use]
+    pub fn idle_timeout() -> Option<Duration> {
+        limit("DEVCADE_IDLE_TIMEOUT", 10).map(|minutes| Duration::from_secs(minutes * 60))
+    }
+
+    /**
+     * Get how often cached save data is flushed to disk in the background. This is read from
+     * DEVCADE_FLUSH_INTERVAL in seconds, where 0 disables periodic flushing. If the value is not
+     * set or invalid, it will default to 60 seconds.
+     */
+    #[must_use]
+    pub fn flush_interval() -> Option<Duration> {
+        limit("DEVCADE_FLUSH_INTERVAL", 60).map(Duration::from_secs)
+    }
+
+    /**
+     * Get how long a response from the API is used without asking the API whether it changed.
+     * This is read from DEVCADE_API_CACHE_TTL in seconds, where 0 checks with the API every time.
+     * If the value is not set or invalid, it will default to 5 minutes.
+     */
+    #[must_use]
+    pub fn api_cache_ttl() -> Duration {
+        // 0 doesn't disable the cache, it just never trusts it without asking the API
+        Duration::from_secs(limit("DEVCADE_API_CACHE_TTL", 300).unwrap_or(0))
+    }
+
+    /**
+     * Get the directory responses from the API are cached in, so they survive a restart. This is
+     * read from DEVCADE_API_CACHE_DIR. If the value is not set, it will default to
+     * '$HOME/.cache/devcade/api'.
+     */
+    #[must_use]
+    pub fn api_cache_dir() -> PathBuf {
+        match env::var("DEVCADE_API_CACHE_DIR") {
+            Ok(dir) if !dir.trim().is_empty() => PathBuf::from(dir.trim()),
+            _ => {
+                let h = env::var("HOME").unwrap(); // if HOME is not set we have bigger issues
+                Path::new(&h).join(".cache").join("devcade").join("api")
+            }
+        }
+    }
+
+    /**
+     * Get how long connecting to the API may take. This is read from DEVCADE_API_CONNECT_TIMEOUT
+     * in seconds, where 0 waits forever. If the value is not set or invalid, it will default to 5
+     * seconds.
+     */
+    #[must_use]
+    pub fn api_connect_timeout() -> Option<Duration> {
+        limit("DEVCADE_API_CONNECT_TIMEOUT", 5).map(Duration::from_secs)
+    }
+
+    /**
+     * Get how long the API may go without sending anything before a request is given up on. This
+     * is read from DEVCADE_API_READ_TIMEOUT in seconds, where 0 waits forever. If the value is not
+     * set or invalid, it will default to 30 seconds.
+     */
+    #[must_use]
+    pub fn api_read_timeout() -> Option<Duration> {
+        limit("DEVCADE_API_READ_TIMEOUT", 30).map(Duration::from_secs)
+    }
+
+    /**
+     * Get the backend save data is stored in, either 'json' (one file per group) or 'sqlite'.
+     * This is read from DEVCADE_SAVE_BACKEND. If the value is not set, it will default to json.
+     */
+    #[must_use]
+    pub fn save_backend() -> String {
+        match env::var("DEVCADE_SAVE_BACKEND") {
+            Ok(backend) => backend.trim().to_lowercase(),
+            Err(_) => String::from("json"),
+        }
+    }
+
+    /**
+     * Get the URL of the server save data is synced with, so that save data is shared between
+     * cabinets. This is read from DEVCADE_SAVE_SYNC_URL. If the value is not set or empty, save
+     * data is only kept on this cabinet.
+     */
+    #[must_use]
+    pub fn save_sync_url() -> Option<String> {
+        match env::var("DEVCADE_SAVE_SYNC_URL") {
+            Ok(url) if !url.trim().is_empty() => Some(url.trim().trim_end_matches('/').to_string()),
+            _ => None,
+        }
+    }
+
+    /**
+     * Get the token sent to the save sync server as a bearer token, if there is one. This is read
+     * from DEVCADE_SAVE_SYNC_TOKEN.
+     */
+    #[must_use]
+    pub fn save_sync_token() -> Option<String> {
+        match env::var("DEVCADE_SAVE_SYNC_TOKEN") {
+            Ok(token) if !token.trim().is_empty() => Some(token.trim().to_string()),
+            _ => None,
+        }
+    }
+
+    /**
+     * Limits on how much save data a single game may store. `None` means unlimited.
+     */
+    #[derive(Debug, Clone, Copy)]
+    pub struct SaveQuota {
+        /// Keys across all of the game's groups
+        pub max_keys: Option<u64>,
+        /// Size of a single value, in bytes
+        pub max_value_bytes: Option<u64>,
+        /// Size of all keys and values, in bytes
+        pub max_total_bytes: Option<u64>,
+    }
+
+    /**
+     * Get the per-game save data limits. These are read from DEVCADE_SAVE_MAX_KEYS,
+     * DEVCADE_SAVE_MAX_VALUE_BYTES and DEVCADE_SAVE_MAX_TOTAL_BYTES, where 0 disables a limit. If a
+     * value is not set or invalid, it will default to 10000 keys, 1 MiB and 16 MiB respectively.
+     */
+    #[must_use]
+    pub fn save_quota() -> SaveQuota {
+        SaveQuota {
+            max_keys: limit("DEVCADE_SAVE_MAX_KEYS", 10_000),
+            max_value_bytes: limit("DEVCADE_SAVE_MAX_VALUE_BYTES", 1024 * 1024),
+            max_total_bytes: limit("DEVCADE_SAVE_MAX_TOTAL_BYTES", 16 * 1024 * 1024),
+        }
+    }
+
+    /**
+     * Read a number from the environment, falling back to `default` if it isn't set or invalid.
+     * 0 comes back as `None`, which means unlimited or disabled.
+     */
+    fn limit(name: &str, default: u64) -> Option<u64> {
+        let value = match env::var(name) {
+            Ok(value) => match value.trim().parse::<u64>() {
+                Ok(value) => value,
+                Err(e) => {
+                    log!(
+                        Level::Warn,
+                        "Invalid {} '{}', falling back to {}: {}",
+                        name,
+                        value,
+                        default,
+                        e
+                    );
+                    default
+                }
+            },
+            Err(_) => default,
+        };
+
+        match value {
+            0 => None,
+            value => Some(value),
+        }
+    }
+
     /**
      * Sets whether the API will interact with the production or development API.
      */
@@ -90,5 +287,6 @@ pub mod env {
     pub fn set_production(prod: bool) {
         log!(Level::Info, "Setting production to {}", prod);
         *PRODUCTION.lock().unwrap() = prod;
+        crate::events::publish(devcade_onboard_types::Event::ApiModeChanged { production: prod });
     }
 }
@@ -1,8 +1,45 @@
-use backend::env::devcade_path;
+use backend::env::{devcade_path, flush_interval};
+use backend::nfc::NFC_CLIENT;
+use backend::persistence;
 use backend::servers::path::{game_pipe, onboard_pipe};
 use backend::servers::ThreadHandles;
+use backend::supervisor;
 use log::{log, Level};
 use tokio::fs;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{interval_at, Instant, Interval};
+
+/**
+ * Wait for the next periodic flush, or forever if periodic flushing is disabled.
+ */
+async fn next_flush(timer: &mut Option<Interval>) {
+    match timer {
+        Some(timer) => {
+            timer.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/**
+ * Stop the running game, flush all cached save data and exit. Called when the backend is asked to
+ * stop, so no player progress is lost.
+ */
+async fn shutdown(signal: &str) -> ! {
+    log!(
+        Level::Info,
+        "Received {}, stopping the running game, flushing save data and exiting",
+        signal
+    );
+    // The game gets to exit properly, so whatever it saves on the way out and its play session
+    // are kept, and its sandbox isn't left running
+    supervisor::stop().await;
+    if let Err(e) = persistence::flush().await {
+        log!(Level::Error, "Failed to flush save data on shutdown: {}", e);
+        std::process::exit(1);
+    }
+    std::process::exit(0);
+}
 
 #[tokio::main]
 async fn main() -> ! {
@@ -23,6 +60,14 @@ async fn main() -> ! {
     }
     env_logger::init();
 
+    // Open save storage up front, so a broken database or migration stops startup instead of the
+    // first game's saves
+    persistence::global().await;
+
+    // Start the NFC reader thread now, so taps are announced to subscribers before anybody asks
+    // for a tag
+    lazy_static::initialize(&NFC_CLIENT);
+
     let mut handles: ThreadHandles = ThreadHandles::new();
 
     handles.restart_onboard(onboard_pipe());
@@ -31,9 +76,24 @@ async fn main() -> ! {
 
     // TODO Gatekeeper / Authentication
 
+    let mut flush_timer =
+        flush_interval().map(|period| interval_at(Instant::now() + period, period));
+    let mut sigterm = signal(SignalKind::terminate()).expect("Couldn't listen for SIGTERM");
+    let mut sigint = signal(SignalKind::interrupt()).expect("Couldn't listen for SIGINT");
+
     // Main loop
     loop {
-        tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
+        tokio::select! {
+            _ = tokio::time::sleep(tokio::time::Duration::from_millis(1000)) => {}
+            _ = next_flush(&mut flush_timer) => {
+                log!(Level::Trace, "Periodic flush of save data");
+                if let Err(e) = persistence::flush().await {
+                    log!(Level::Warn, "Periodic flush of save data failed: {}", e);
+                }
+            }
+            _ = sigterm.recv() => shutdown("SIGTERM").await,
+            _ = sigint.recv() => shutdown("SIGINT").await,
+        }
         // Check if any of the handles have finished
         if let Some(err) = handles.onboard_error() {
             log!(Level::Error, "Onboard thread has panicked: {}", err);
@@ -1,14 +1,15 @@
 use crate::api::current_game;
-use devcade_onboard_types::{Map, Value};
+use devcade_onboard_types::error::{ErrorKind, ResponseError};
+use devcade_onboard_types::{Event, Map, Player, Value};
 use gatekeeper_members::{GateKeeperMemberListener, RealmType};
 use lazy_static::lazy_static;
 use ringbuffer::{AllocRingBuffer, RingBuffer};
 use std::fmt;
 use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
 use std::thread;
 use std::thread::JoinHandle;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::sync::oneshot;
 use tokio::sync::Mutex;
 
@@ -47,88 +48,163 @@ impl Default for NfcClient {
 
 const NFC_DEVICE_NAME: &str = "pn532_uart:/dev/ttyACM0";
 
+/**
+ * How long the reader is kept open after it was last needed
+ */
+const LISTENER_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/**
+ * How often the reader thread checks whether anybody subscribed to events while it is idle
+ */
+const SUBSCRIBER_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/**
+ * A tag that stays on the reader is read again on every poll. It is only announced again after
+ * being there this long.
+ */
+const TAG_REPEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/**
+ * How long to wait between polls of the reader while watching it for taps
+ */
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/**
+ * A tag read while watching is kept this long for the next request for tags, so a member who taps
+ * before the game asks isn't ignored
+ */
+const UNCLAIMED_TAG_EXPIRY: Duration = Duration::from_secs(10);
+
 impl NfcClient {
     fn run(rx: Receiver<NfcRequest>) {
         let mut association_ids: AllocRingBuffer<(String, String)> = AllocRingBuffer::new(8);
+        let mut listener: Option<GateKeeperMemberListener> = None;
+        let mut last_used = Instant::now();
+        let mut last_tag: Option<(String, Instant)> = None;
+        // The last tag read while watching, until a request for tags takes it
+        let mut unclaimed: Option<(String, Instant)> = None;
+        // The reader couldn't be opened for watching, so don't try again until then
+        let mut retry_watching_at = Instant::now();
         loop {
-            // Unwrap rationale: If the main thread is crashed, not much we can do
-            let mut callback = rx.recv().unwrap();
-            // Unwrap rationale: If we can't allocate memory, we're not long for this world anyways
-            let mut listener = match GateKeeperMemberListener::new(
-                NFC_DEVICE_NAME.to_string(),
-                RealmType::MemberProjects,
-            ) {
-                Some(listener) => listener,
-                None => {
-                    log::error!("Couldn't build Gatekeeper listener?");
-                    // Unwrap rationale: If the main thread is crashed, not much we can do
-                    match callback {
-                        NfcRequest::User { callback, .. } => callback.send(None).unwrap(),
-                        NfcRequest::Tags { callback } => callback.send(None).unwrap(),
-                    }
-                    continue;
-                }
+            // While anybody is subscribed to events, the reader is polled for tags between
+            // requests, so that taps are announced without anybody asking for them
+            let watching = crate::events::has_subscribers() && Instant::now() >= retry_watching_at;
+            let wait = if watching {
+                WATCH_POLL_INTERVAL
+            } else {
+                SUBSCRIBER_CHECK_INTERVAL
             };
+            let request = match rx.recv_timeout(wait) {
+                Ok(request) => Some(request),
+                Err(RecvTimeoutError::Timeout) => None,
+                // The client is gone, so nobody can ask for anything anymore
+                Err(RecvTimeoutError::Disconnected) => return,
+            };
+            if request.is_none() && !watching {
+                if last_used.elapsed() >= LISTENER_IDLE_TIMEOUT {
+                    listener = None;
+                }
+                continue;
+            }
+            last_used = Instant::now();
 
-            loop {
-                match callback {
-                    NfcRequest::User {
-                        callback,
-                        association_id: association_handle,
-                    } => {
-                        let association_id =
-                            (&association_ids)
-                                .into_iter()
-                                .find_map(|(handle, association_id)| {
-                                    match handle == &association_handle {
-                                        true => Some(association_id),
-                                        false => None,
-                                    }
-                                });
-                        callback
-                            .send(
-                                association_id
-                                    .and_then(|association_id| {
-                                        listener.fetch_user(association_id.clone()).ok()
-                                    })
-                                    .and_then(|user| user["user"].as_object().cloned()),
-                            )
-                            .unwrap();
-                    }
-                    NfcRequest::Tags { callback } => {
-                        let association_id =
-                            listener
-                                .poll_for_user()
-                                .map(|association_id| {
-                                    match (&association_ids).into_iter().find(
-                                        |(_, candidate_association_id)| {
-                                            candidate_association_id == &association_id
-                                        },
-                                    ) {
-                                        Some((handle, _)) => handle.clone(),
-                                        None => {
-                                            let game_uuid = current_game().id;
-                                            let handle = sha256::digest(format!(
-                                                "{association_id}:{game_uuid}"
-                                            ));
-                                            association_ids.push((handle.clone(), association_id));
-                                            handle
-                                        }
-                                    }
-                                });
-                        // Unwrap rationale: If the main thread is crashed, not much we can do
-                        callback.send(association_id).unwrap();
-                    }
+            if listener.is_none() {
+                listener = GateKeeperMemberListener::new(
+                    NFC_DEVICE_NAME.to_string(),
+                    RealmType::MemberProjects,
+                );
+            }
+            let Some(listener) = listener.as_mut() else {
+                log::error!("Couldn't build Gatekeeper listener?");
+                match request {
+                    Some(NfcRequest::User { callback, .. }) => Self::reply(callback, None),
+                    Some(NfcRequest::Tags { callback }) => Self::reply(callback, None),
+                    None => retry_watching_at = Instant::now() + LISTENER_IDLE_TIMEOUT,
                 }
+                continue;
+            };
 
-                if let Ok(new_request) = rx.recv_timeout(Duration::from_secs(30)) {
-                    callback = new_request;
-                } else {
-                    break;
+            match request {
+                Some(NfcRequest::User {
+                    callback,
+                    association_id: association_handle,
+                }) => {
+                    let association_id =
+                        (&association_ids)
+                            .into_iter()
+                            .find_map(|(handle, association_id)| {
+                                match handle == &association_handle {
+                                    true => Some(association_id),
+                                    false => None,
+                                }
+                            });
+                    let user = association_id
+                        .and_then(|association_id| listener.fetch_user(association_id.clone()).ok())
+                        .and_then(|user| user["user"].as_object().cloned());
+                    Self::reply(callback, user);
+                }
+                Some(NfcRequest::Tags { callback }) => {
+                    let association_id = match unclaimed.take() {
+                        Some((association_id, at)) if at.elapsed() < UNCLAIMED_TAG_EXPIRY => {
+                            Some(association_id)
+                        }
+                        _ => Self::read_tag(listener, &mut last_tag),
+                    };
+                    let association_id = association_id.map(|association_id| {
+                        // The handle has to be derived for the current game every
+                        // time, otherwise a member would get the same handle in every
+                        // game they play after the first
+                        let game_uuid = current_game().id;
+                        let handle = sha256::digest(format!("{association_id}:{game_uuid}"));
+                        if !(&association_ids)
+                            .into_iter()
+                            .any(|(candidate, _)| candidate == &handle)
+                        {
+                            association_ids.push((handle.clone(), association_id));
+                        }
+                        handle
+                    });
+                    Self::reply(callback, association_id);
+                }
+                None => {
+                    if let Some(association_id) = Self::read_tag(listener, &mut last_tag) {
+                        unclaimed = Some((association_id, Instant::now()));
+                    }
                 }
             }
         }
     }
+
+    /**
+     * Answer a request. Whoever asked may have given up on the answer (e.g. the request was
+     * cancelled), which is nothing to worry about.
+     */
+    fn reply<T>(callback: oneshot::Sender<T>, answer: T) {
+        if callback.send(answer).is_err() {
+            log::debug!("NFC request was given up on before it was answered");
+        }
+    }
+
+    /**
+     * Poll the reader for a tag, telling event subscribers if one was presented. The event
+     * doesn't identify the member, since their handle depends on the game asking for it.
+     */
+    fn read_tag(
+        listener: &mut GateKeeperMemberListener,
+        last_tag: &mut Option<(String, Instant)>,
+    ) -> Option<String> {
+        let association_id = listener.poll_for_user()?;
+        let repeated = matches!(
+            last_tag,
+            Some((last, at)) if last == &association_id && at.elapsed() < TAG_REPEAT_INTERVAL
+        );
+        if !repeated {
+            crate::events::publish(Event::NfcTagPresented { player: Player::P1 });
+        }
+        *last_tag = Some((association_id.clone(), Instant::now()));
+        Some(association_id)
+    }
+
     pub async fn submit(&self) -> Result<Option<String>, Box<dyn std::error::Error>> {
         let (tx, rx) = oneshot::channel();
 
@@ -150,7 +226,11 @@ impl NfcClient {
         })?;
         match rx.await? {
             Some(user) => Ok(user),
-            None => Err(anyhow::anyhow!("User not found with that association ID")),
+            None => Err(ResponseError::new(
+                ErrorKind::NotFound,
+                "User not found with that association ID",
+            )
+            .into()),
         }
     }
 }
@@ -165,3 +245,32 @@ impl fmt::Display for NfcThreadError {
 }
 
 impl std::error::Error for NfcThreadError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn cancelled_requests_do_not_stop_the_reader() {
+        let client = NfcClient::default();
+
+        // Cancelling a request drops what its answer would be sent to, but it stays queued
+        let requests = client.request_queue.lock().await;
+        let (callback, answer) = oneshot::channel();
+        drop(answer);
+        requests.send(NfcRequest::Tags { callback }).unwrap();
+        let (callback, answer) = oneshot::channel();
+        drop(answer);
+        requests
+            .send(NfcRequest::User {
+                association_id: String::new(),
+                callback,
+            })
+            .unwrap();
+        drop(requests);
+
+        // There's no reader here, so nobody is found, but the thread still answers
+        assert_eq!(client.submit().await.unwrap(), None);
+        assert!(!client.thread.is_finished());
+    }
+}
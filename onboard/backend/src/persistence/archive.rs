@@ -0,0 +1,208 @@
+use anyhow::Error;
+use devcade_onboard_types::error::{ErrorKind, ResponseError};
+use devcade_onboard_types::{
+    ArchiveManifest, ImportMode, ImportSummary, Map, SaveArchive, Value, SAVE_ARCHIVE_FORMAT,
+    SAVE_ARCHIVE_VERSION,
+};
+use std::collections::{BTreeMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{game_of, mark_modified, names, Persistence};
+
+fn invalid(message: String) -> Error {
+    ResponseError::new(ErrorKind::InvalidRequest, message).into()
+}
+
+impl Persistence {
+    /**
+     * Export the save data of one game (including its players' save data), or of every game if
+     * `game_id` is `None`. Changes that haven't been flushed yet are included. Groups that aren't
+     * cached are read from the storage backend without being added to the cache.
+     */
+    pub async fn export(&self, game_id: Option<&str>) -> Result<SaveArchive, Error> {
+        log::info!("Exporting save data of {}", game_id.unwrap_or("all games"));
+        let prefix = match game_id {
+            Some(game_id) => {
+                names::check_game_id(game_id)?;
+                format!("{game_id}/")
+            }
+            None => String::new(),
+        };
+
+        let data = self.db.lock().await;
+
+        let mut groups: BTreeMap<String, Map<String, Value>> = BTreeMap::new();
+        for group in self.storage.list_groups(&prefix).await? {
+            if data.contains_key(&group.name) {
+                continue;
+            }
+            let inner = self.storage.load_group(&group.name).await?;
+            groups.insert(group.name, inner.into_iter().collect());
+        }
+        for (group, inner) in data.iter() {
+            if group.starts_with(&prefix) && !inner.is_empty() {
+                let inner = inner
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect();
+                groups.insert(group.clone(), inner);
+            }
+        }
+        groups.retain(|_, inner| !inner.is_empty());
+
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or_default();
+        Ok(SaveArchive {
+            manifest: ArchiveManifest {
+                format: SAVE_ARCHIVE_FORMAT.to_string(),
+                version: SAVE_ARCHIVE_VERSION,
+                created_at,
+                game_id: game_id.map(str::to_string),
+                groups: groups.len() as u64,
+                keys: groups.values().map(|inner| inner.len() as u64).sum(),
+            },
+            groups,
+        })
+    }
+
+    /**
+     * Import an archive made by [`Persistence::export`] and flush it to the storage backend. The
+     * whole archive is checked before anything is changed, so a malformed archive is rejected
+     * without leaving a partial import behind. Imports are made by an admin, so they aren't held to
+     * the save data quotas.
+     */
+    pub async fn import(
+        &self,
+        archive: &SaveArchive,
+        mode: ImportMode,
+    ) -> Result<ImportSummary, Error> {
+        log::info!(
+            "Importing {} save groups ({mode:?})",
+            archive.manifest.groups
+        );
+        check_archive(archive)?;
+        if mode == ImportMode::Replace
+            && archive.manifest.game_id.is_none()
+            && archive.groups.is_empty()
+        {
+            return Err(invalid(String::from(
+                "Refusing to replace the save data of every game with an empty archive, force the \
+                 replace to remove everything",
+            )));
+        }
+
+        let mut summary = ImportSummary::default();
+        {
+            let mut data = self.db.lock().await;
+            let mut mod_list = self.db_modified.lock().await;
+
+            if matches!(mode, ImportMode::Replace | ImportMode::ForceReplace) {
+                // A single game archive only replaces that game, a full archive replaces everything
+                let prefix = match &archive.manifest.game_id {
+                    Some(game_id) => format!("{game_id}/"),
+                    None => String::new(),
+                };
+                let mut existing: HashSet<String> = self
+                    .storage
+                    .list_groups(&prefix)
+                    .await?
+                    .into_iter()
+                    .map(|group| group.name)
+                    .collect();
+                existing.extend(
+                    data.keys()
+                        .filter(|group| group.starts_with(&prefix))
+                        .cloned(),
+                );
+
+                for group in existing {
+                    let inner = self.get_submap_or_load(&mut data, &group).await?;
+                    if inner.is_empty() {
+                        continue;
+                    }
+                    for (key, _) in inner.drain() {
+                        mark_modified(&mut mod_list, &group, &key);
+                    }
+                    summary.removed_groups += 1;
+                }
+            }
+
+            for (group, values) in &archive.groups {
+                let inner = self.get_submap_or_load(&mut data, group).await?;
+                for (key, value) in values {
+                    inner.insert(key.clone(), value.clone());
+                    mark_modified(&mut mod_list, group, key);
+                }
+                summary.groups += 1;
+                summary.keys += values.len() as u64;
+            }
+        }
+        // Imports aren't counted towards quotas one entry at a time
+        self.forget_usage(archive.manifest.game_id.as_deref());
+
+        self.flush().await?;
+        Ok(summary)
+    }
+}
+
+/**
+ * Make sure an archive is something [`Persistence::export`] could have made: a known format and
+ * version, a manifest that matches the contents, and only safe group and key names that belong to
+ * the game the archive is for.
+ */
+pub fn check_archive(archive: &SaveArchive) -> Result<(), Error> {
+    let manifest = &archive.manifest;
+    if manifest.format != SAVE_ARCHIVE_FORMAT {
+        return Err(invalid(format!(
+            "Not a save archive (format is '{}', expected '{SAVE_ARCHIVE_FORMAT}')",
+            manifest.format.escape_debug()
+        )));
+    }
+    if manifest.version != SAVE_ARCHIVE_VERSION {
+        return Err(invalid(format!(
+            "Unsupported save archive version {} (expected {SAVE_ARCHIVE_VERSION})",
+            manifest.version
+        )));
+    }
+    if let Some(game_id) = &manifest.game_id {
+        names::check_game_id(game_id)?;
+    }
+
+    let keys: u64 = archive
+        .groups
+        .values()
+        .map(|inner| inner.len() as u64)
+        .sum();
+    if manifest.groups != archive.groups.len() as u64 || manifest.keys != keys {
+        return Err(invalid(format!(
+            "Save archive manifest lists {} groups and {} keys, but it contains {} groups and {keys} keys",
+            manifest.groups,
+            manifest.keys,
+            archive.groups.len()
+        )));
+    }
+
+    for (group, inner) in &archive.groups {
+        names::check_group(group)?;
+        if let Some(game_id) = &manifest.game_id {
+            if game_of(group) != Some(game_id.as_str()) {
+                return Err(invalid(format!(
+                    "Save group '{}' doesn't belong to game {game_id}",
+                    group.escape_debug()
+                )));
+            }
+        }
+        if inner.is_empty() {
+            return Err(invalid(format!(
+                "Save group '{}' is empty",
+                group.escape_debug()
+            )));
+        }
+        for key in inner.keys() {
+            names::check_key(key)?;
+        }
+    }
+    Ok(())
+}
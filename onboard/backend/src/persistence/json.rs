@@ -0,0 +1,193 @@
+use anyhow::Error;
+use async_trait::async_trait;
+use devcade_onboard_types::error::{ErrorKind, ResponseError};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::task;
+
+use super::{GroupData, GroupWrite, StorageBackend, StoredGroup};
+use crate::stats::unix_secs;
+
+/**
+ * The original save layout: every group is a JSON object in `<root>/<group>.save`, so the group
+ * `game-id/level-1/scores` lives in `<root>/game-id/level-1/scores.save`. Every flush rewrites the
+ * whole file of each changed group. Files written before values could be any JSON hold only
+ * strings, which load as string values.
+ */
+pub struct JsonStore {
+    root: PathBuf,
+}
+
+impl JsonStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        JsonStore { root: root.into() }
+    }
+
+    fn path(&self, group: &str) -> PathBuf {
+        self.root.join(format!("{group}.save"))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for JsonStore {
+    async fn load_group(&self, group: &str) -> Result<GroupData, Error> {
+        let path = self.path(group);
+        load_save_file(&path).await.map_err(|err| {
+            ResponseError::new(ErrorKind::Persistence, format!("Couldn't load {path:?}"))
+                .with_details(err)
+                .into()
+        })
+    }
+
+    async fn write_groups(&self, writes: &[GroupWrite<'_>]) -> Result<(), Error> {
+        for write in writes {
+            let path = self.path(write.group);
+            log::debug!("Flushing to {path:?}");
+            let written = async {
+                // An empty group has nothing worth keeping, so don't leave an empty file (or a
+                // backup that would be 'recovered' on the next load) behind
+                if write.values.is_empty() {
+                    for file in [path.clone(), with_suffix(&path, ".bak")] {
+                        if file.exists() {
+                            fs::remove_file(file).await?;
+                        }
+                    }
+                    return Ok(());
+                }
+                let dir = path.parent().expect("path failed to have parents");
+                if !dir.exists() {
+                    fs::create_dir_all(dir).await?;
+                }
+                write_save_file(&path, serde_json::to_string(write.values)?.as_bytes()).await?;
+                Ok(()) as Result<(), Error>
+            };
+            written.await.map_err(|err| {
+                ResponseError::new(ErrorKind::Persistence, format!("Couldn't flush {path:?}"))
+                    .with_details(err)
+            })?;
+        }
+        Ok(())
+    }
+
+    async fn list_groups(&self, prefix: &str) -> Result<Vec<StoredGroup>, Error> {
+        let dir = self.root.join(prefix);
+        // Walking the directory tree is blocking, so keep it off the async threads
+        let walked = {
+            let dir = dir.clone();
+            task::spawn_blocking(move || {
+                let mut files = vec![];
+                find_save_files(&dir, &mut files).map(|_| files)
+            })
+            .await?
+        };
+        let files = walked.map_err(|err| {
+            ResponseError::new(
+                ErrorKind::Persistence,
+                format!("Couldn't list save files in {dir:?}"),
+            )
+            .with_details(err)
+        })?;
+
+        let mut groups = vec![];
+        for file in files {
+            let name = file
+                .strip_prefix(&self.root)
+                .ok()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".save"));
+            if let Some(name) = name {
+                groups.push(StoredGroup {
+                    name: name.to_string(),
+                    bytes: fs::metadata(&file).await?.len(),
+                });
+            }
+        }
+        Ok(groups)
+    }
+}
+
+/**
+ * Recursively collect every `.save` file under `dir`. A missing directory has no save files.
+ * */
+fn find_save_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), Error> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_save_files(&path, files)?;
+        } else if path.extension() == Some(OsStr::new("save")) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Get `path` with `suffix` appended to the file name, e.g. `a/b.save` -> `a/b.save.bak`
+ */
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/**
+ * Write a save file so that a crash at any point leaves either the old or the new version on
+ * disk, never a truncated one. The data is written to a temp file and fsynced, the current version
+ * is kept as a last-good backup, and the temp file is renamed over the save file.
+ */
+pub(super) async fn write_save_file(path: &Path, data: &[u8]) -> Result<(), Error> {
+    let tmp = with_suffix(path, ".tmp");
+    let mut file = fs::File::create(&tmp).await?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    if path.exists() {
+        fs::rename(path, with_suffix(path, ".bak")).await?;
+    }
+    fs::rename(&tmp, path).await?;
+
+    // Make sure the renames themselves survive a power cut
+    if let Some(dir) = path.parent() {
+        fs::File::open(dir).await?.sync_all().await?;
+    }
+    Ok(())
+}
+
+/**
+ * Load a save file, recovering from a crash during a previous flush. A save file that can't be
+ * parsed is moved out of the way (so it can be inspected later) and the last-good backup is used
+ * and restored instead. If there is no usable data at all, the group starts out empty.
+ */
+async fn load_save_file(path: &Path) -> Result<GroupData, Error> {
+    let backup = with_suffix(path, ".bak");
+    for candidate in [path, backup.as_path()] {
+        if !candidate.exists() {
+            continue;
+        }
+        let contents = fs::read_to_string(candidate).await?;
+        match serde_json::from_str::<GroupData>(&contents) {
+            Ok(map) if candidate == path => return Ok(map),
+            Ok(map) => {
+                log::warn!("Recovered {path:?} from last good backup");
+                write_save_file(path, contents.as_bytes()).await?;
+                return Ok(map);
+            }
+            Err(err) => {
+                let quarantine = with_suffix(
+                    candidate,
+                    &format!(".corrupt-{}", unix_secs(SystemTime::now())),
+                );
+                log::error!("{candidate:?} is corrupt ({err}), moving it to {quarantine:?}");
+                fs::rename(candidate, &quarantine).await?;
+            }
+        }
+    }
+    Ok(GroupData::new())
+}
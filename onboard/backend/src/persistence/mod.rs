@@ -0,0 +1,959 @@
+use anyhow::Error;
+use devcade_onboard_types::error::{ErrorKind, ResponseError};
+use devcade_onboard_types::{
+    Bytes, ImportMode, ImportSummary, SaveArchive, StorageUsage, SwapResult, Value,
+};
+use lazy_static::lazy_static;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use tokio::sync::{Mutex, OnceCell};
+
+use crate::env::{save_quota, save_sync_token, save_sync_url, SaveQuota};
+
+/**
+ * Exporting and importing save data as a single archive
+ */
+pub mod archive;
+
+/**
+ * Save data stored as one JSON file per group
+ */
+pub mod json;
+
+/**
+ * Validation of the group and key names games send
+ */
+pub mod names;
+
+/**
+ * Save data stored in an embedded SQLite database
+ */
+pub mod sqlite;
+
+/**
+ * The interface every save data backend implements
+ */
+pub mod storage;
+
+/**
+ * Sharing save data between cabinets through a sync server
+ *
+ * Groups are synced as a whole, tagged with when they were last changed (in milliseconds since the
+ * unix epoch). The server has to support two requests:
+ * - `POST {url}/groups` with a JSON array of [`sync::RemoteGroup`]s. The server keeps each group
+ *   unless it already has a newer version of it. Any 2xx status means the groups were received.
+ * - `GET {url}/groups?prefix={game-id}/`, answered with a JSON array of the server's latest
+ *   version of every group whose name starts with the prefix.
+ *
+ * A group without any values was cleared, and is removed when it is pulled.
+ */
+pub mod sync;
+
+#[cfg(test)]
+mod tests;
+
+pub use json::JsonStore;
+pub use names::game_group;
+pub use sqlite::SqliteStore;
+pub use storage::{GroupData, GroupWrite, StorageBackend, StoredGroup};
+pub use sync::{RemoteGroup, SyncClient};
+
+lazy_static! {
+    // basically just checks if a user 'devcade' exists. If so, assumes that this is running on the
+    // machine, and saves to the homedir. Otherwise, saves to the cwd.
+    static ref ON_MACHINE: bool = Path::new("/home/devcade").exists();
+    // The save data of every game, opened on first use
+    static ref PERSISTENCE: OnceCell<Persistence> = OnceCell::new();
+}
+
+/**
+ * Directory inside a game's save directory that player save data is kept in, one subdirectory per
+ * player handle. It is hidden from the game's shared groups.
+ */
+pub const PLAYER_SAVE_DIR: &str = ".players";
+
+/**
+ * A store of save data. Games save to and load from an in-memory cache of groups, which is
+ * written to the storage backend when it is flushed.
+ *
+ * Group names start with the ID of the game they belong to, but can be further subdivided by the
+ * game using `/` (e.g. `game-id/level-1/scores`). Names that come from a game have to go through
+ * [`game_group`] first, anything else that isn't a safe path is rejected.
+ */
+pub struct Persistence {
+    db: Mutex<HashMap<String, GroupData>>,
+    // Changes to each group since the last flush
+    db_modified: Mutex<HashMap<String, ModifiedGroup>>,
+    // What each game is storing, for checking quotas. A game is counted up once, when it first
+    // needs to be checked, and kept up to date as it changes from then on.
+    quota_usage: std::sync::Mutex<HashMap<String, GameUsage>>,
+    storage: Box<dyn StorageBackend>,
+    quota: SaveQuota,
+    sync: Option<SyncClient>,
+}
+
+/**
+ * Changes to a group that haven't been flushed yet
+ */
+#[derive(Debug, Default)]
+struct ModifiedGroup {
+    /// Keys that were set or removed
+    keys: HashSet<String>,
+    /// When the group was last changed, in milliseconds since the unix epoch
+    changed_at: u64,
+}
+
+/**
+ * The keys and bytes a game is storing, including changes that haven't been flushed yet
+ */
+#[derive(Debug, Default, Clone, Copy)]
+struct GameUsage {
+    keys: u64,
+    bytes: u64,
+}
+
+/**
+ * Directory all persistent data (saves, play history) is stored in
+ */
+pub fn save_root() -> &'static Path {
+    Path::new(if *ON_MACHINE {
+        "/home/devcade/.save"
+    } else {
+        "./.save"
+    })
+}
+
+/**
+ * Get the store all games save to, opening it the first time. Call this on startup to make sure
+ * the storage backend can be opened (and any migration has run) before anything is served,
+ * instead of failing on the first save.
+ */
+pub async fn global() -> &'static Persistence {
+    PERSISTENCE
+        .get_or_init(|| async {
+            let persistence = Persistence::new(storage::from_env(save_root()).await, save_quota());
+            match save_sync_url() {
+                Some(url) => {
+                    log::info!("Syncing save data with {url}");
+                    let state = save_root().join("sync-state.json");
+                    persistence.with_sync(SyncClient::new(url, save_sync_token(), state))
+                }
+                None => persistence,
+            }
+        })
+        .await
+}
+
+// currently saves to the devcade machine (or local machine if running locally) in the future,
+// should ideally use a remote database / something else.
+pub async fn save(group: &str, key: &str, value: impl Into<Value>) -> Result<(), Error> {
+    global().await.save(group, key, value).await
+}
+
+/**
+ * Load a value from using a group and key
+ */
+pub async fn load(group: &str, key: &str) -> Result<Value, Error> {
+    global().await.load(group, key).await
+}
+
+/**
+ * Load bytes saved with [`Bytes`] from using a group and key
+ */
+pub async fn load_bytes(group: &str, key: &str) -> Result<Bytes, Error> {
+    global().await.load_bytes(group, key).await
+}
+
+/**
+ * Atomically add to an integer value
+ */
+pub async fn increment(group: &str, key: &str, amount: i64) -> Result<Value, Error> {
+    global().await.increment(group, key, amount).await
+}
+
+/**
+ * Atomically replace a value if it is what the caller expects it to be
+ */
+pub async fn compare_and_swap(
+    group: &str,
+    key: &str,
+    expected: Option<Value>,
+    new: Value,
+) -> Result<SwapResult, Error> {
+    global()
+        .await
+        .compare_and_swap(group, key, expected, new)
+        .await
+}
+
+/**
+ * Remove a key from a group
+ */
+pub async fn delete(group: &str, key: &str) -> Result<(), Error> {
+    global().await.delete(group, key).await
+}
+
+/**
+ * List all keys stored in a group
+ */
+pub async fn list_keys(group: &str) -> Result<Vec<String>, Error> {
+    global().await.list_keys(group).await
+}
+
+/**
+ * List all groups a game has stored data in
+ */
+pub async fn list_groups(game_id: &str) -> Result<Vec<String>, Error> {
+    global().await.list_groups(game_id).await
+}
+
+/**
+ * Remove every key in a group
+ */
+pub async fn clear_group(group: &str) -> Result<(), Error> {
+    global().await.clear_group(group).await
+}
+
+/**
+ * Flush all pending writes to the storage backend
+ */
+pub async fn flush() -> Result<(), Error> {
+    global().await.flush().await
+}
+
+/**
+ * Replace a game's save data with anything newer on the sync server
+ */
+pub async fn pull(game_id: &str) -> Result<(), Error> {
+    global().await.pull(game_id).await
+}
+
+/**
+ * Report how much save data every game is storing
+ */
+pub async fn usage() -> Result<Vec<StorageUsage>, Error> {
+    global().await.usage().await
+}
+
+/**
+ * Export the save data of one game, or of every game
+ */
+pub async fn export(game_id: Option<&str>) -> Result<SaveArchive, Error> {
+    global().await.export(game_id).await
+}
+
+/**
+ * Import a save archive, merging it with or replacing the save data already stored
+ */
+pub async fn import(archive: &SaveArchive, mode: ImportMode) -> Result<ImportSummary, Error> {
+    global().await.import(archive, mode).await
+}
+
+/**
+ * Flush all changes and clear the in-memory cache
+ */
+pub async fn clear_db() -> Result<(), Error> {
+    global().await.clear_db().await
+}
+
+/**
+ * Get the total number of K, V pairs across the entire cache
+ */
+pub async fn db_cache_size() -> usize {
+    global().await.db_cache_size().await
+}
+
+impl Persistence {
+    pub fn new(storage: Box<dyn StorageBackend>, quota: SaveQuota) -> Self {
+        Persistence {
+            db: Mutex::new(HashMap::new()),
+            db_modified: Mutex::new(HashMap::new()),
+            quota_usage: std::sync::Mutex::new(HashMap::new()),
+            storage,
+            quota,
+            sync: None,
+        }
+    }
+
+    /**
+     * Sync save data with a server. Changed groups are pushed whenever the cache is flushed, and
+     * [`Persistence::pull`] fetches changes made on other cabinets.
+     */
+    pub fn with_sync(mut self, sync: SyncClient) -> Self {
+        self.sync = Some(sync);
+        self
+    }
+
+    /**
+     * Save a value to a group. Saves that would put the game over its quota are rejected.
+     */
+    pub async fn save(&self, group: &str, key: &str, value: impl Into<Value>) -> Result<(), Error> {
+        let value = value.into();
+        log::trace!("saving data to {}/{} ({})", group, key, value);
+        names::check_group(group)?;
+        names::check_key(key)?;
+
+        let mut data = self.db.lock().await;
+        let mut mod_list = self.db_modified.lock().await;
+
+        self.set(&mut data, &mut mod_list, group, key, value).await
+    }
+
+    /**
+     * Load a value from using a group and key
+     */
+    pub async fn load(&self, group: &str, key: &str) -> Result<Value, Error> {
+        log::trace!("loading data from {}/{}", group, key);
+        names::check_group(group)?;
+        names::check_key(key)?;
+
+        let mut data = self.db.lock().await;
+
+        let inner = self.get_submap_or_load(&mut data, group).await?;
+
+        inner.get(key).cloned().ok_or_else(|| {
+            ResponseError::new(
+                ErrorKind::KeyNotFound,
+                format!("Could not find key {key} in group {group}"),
+            )
+            .into()
+        })
+    }
+
+    /**
+     * Load bytes that were saved as a [`Bytes`] value (which is stored as a base64 string)
+     */
+    pub async fn load_bytes(&self, group: &str, key: &str) -> Result<Bytes, Error> {
+        let value = self.load(group, key).await?;
+        value
+            .as_str()
+            .and_then(|encoded| Bytes::from_base64(encoded).ok())
+            .ok_or_else(|| {
+                ResponseError::new(
+                    ErrorKind::InvalidRequest,
+                    format!("Value at {group}/{key} isn't bytes"),
+                )
+                .into()
+            })
+    }
+
+    /**
+     * Add `amount` to an integer value and return the result. A missing key counts as 0, so
+     * counters don't have to be created first.
+     */
+    pub async fn increment(&self, group: &str, key: &str, amount: i64) -> Result<Value, Error> {
+        log::trace!("incrementing {}/{} by {}", group, key, amount);
+        names::check_group(group)?;
+        names::check_key(key)?;
+
+        let mut data = self.db.lock().await;
+        let mut mod_list = self.db_modified.lock().await;
+
+        let inner = self.get_submap_or_load(&mut data, group).await?;
+        let current = match inner.get(key) {
+            None => 0,
+            Some(value) => value.as_i64().ok_or_else(|| {
+                ResponseError::new(
+                    ErrorKind::InvalidRequest,
+                    format!("Value at {group}/{key} isn't an integer"),
+                )
+            })?,
+        };
+        let value = Value::from(current.checked_add(amount).ok_or_else(|| {
+            ResponseError::new(
+                ErrorKind::InvalidRequest,
+                format!("Incrementing {group}/{key} by {amount} overflows"),
+            )
+        })?);
+
+        self.set(&mut data, &mut mod_list, group, key, value.clone())
+            .await?;
+        Ok(value)
+    }
+
+    /**
+     * Replace a value with `new`, but only if it is currently `expected`. An `expected` of `None`
+     * means the key must not exist yet. The result holds the value after the request either way,
+     * so a game that lost a race can retry with it.
+     */
+    pub async fn compare_and_swap(
+        &self,
+        group: &str,
+        key: &str,
+        expected: Option<Value>,
+        new: Value,
+    ) -> Result<SwapResult, Error> {
+        log::trace!("compare and swap {}/{}", group, key);
+        names::check_group(group)?;
+        names::check_key(key)?;
+
+        let mut data = self.db.lock().await;
+        let mut mod_list = self.db_modified.lock().await;
+
+        let inner = self.get_submap_or_load(&mut data, group).await?;
+        let current = inner.get(key).cloned();
+        if current != expected {
+            return Ok(SwapResult {
+                swapped: false,
+                current,
+            });
+        }
+
+        self.set(&mut data, &mut mod_list, group, key, new.clone())
+            .await?;
+        Ok(SwapResult {
+            swapped: true,
+            current: Some(new),
+        })
+    }
+
+    /**
+     * Remove a key from a group. Deleting a key that doesn't exist is not an error.
+     */
+    pub async fn delete(&self, group: &str, key: &str) -> Result<(), Error> {
+        log::trace!("deleting data at {}/{}", group, key);
+        names::check_group(group)?;
+        names::check_key(key)?;
+
+        let mut data = self.db.lock().await;
+        let mut mod_list = self.db_modified.lock().await;
+
+        let inner = self.get_submap_or_load(&mut data, group).await?;
+
+        if let Some(old) = inner.remove(key) {
+            mark_modified(&mut mod_list, group, key);
+            self.adjust_usage(group, Some(entry_size(key, &old)), None);
+        }
+
+        Ok(())
+    }
+
+    /**
+     * List all keys stored in a group, sorted.
+     */
+    pub async fn list_keys(&self, group: &str) -> Result<Vec<String>, Error> {
+        log::trace!("listing keys in {}", group);
+        names::check_group(group)?;
+
+        let mut data = self.db.lock().await;
+
+        let inner = self.get_submap_or_load(&mut data, group).await?;
+
+        let mut keys: Vec<String> = inner.keys().cloned().collect();
+        keys.sort();
+        Ok(keys)
+    }
+
+    /**
+     * List all non-empty groups a game has stored data in, both flushed and still cached, sorted.
+     * Group names are relative to the game, the same way games pass them in. Groups the backend
+     * keeps for itself (player save data, leaderboards), which start with a `.`, aren't listed.
+     */
+    pub async fn list_groups(&self, game_id: &str) -> Result<Vec<String>, Error> {
+        log::trace!("listing groups for {}", game_id);
+        names::check_prefix(game_id)?;
+        let prefix = format!("{game_id}/");
+
+        let data = self.db.lock().await;
+
+        let mut groups = HashSet::new();
+        for group in self.storage.list_groups(&prefix).await? {
+            if let Some(group) = group.name.strip_prefix(&prefix) {
+                if !group.starts_with('.') {
+                    groups.insert(group.to_string());
+                }
+            }
+        }
+
+        // The cache is newer than the backend, including groups that were cleared but not flushed
+        for (full_key, inner) in data.iter() {
+            if let Some(group) = full_key.strip_prefix(&prefix) {
+                if group.starts_with('.') {
+                    continue;
+                }
+                if inner.is_empty() {
+                    groups.remove(group);
+                } else {
+                    groups.insert(group.to_string());
+                }
+            }
+        }
+
+        let mut groups: Vec<String> = groups.into_iter().collect();
+        groups.sort();
+        Ok(groups)
+    }
+
+    /**
+     * Remove every key in a group. The group is removed from the backend on the next flush.
+     */
+    pub async fn clear_group(&self, group: &str) -> Result<(), Error> {
+        log::trace!("clearing group {}", group);
+        names::check_group(group)?;
+
+        let mut data = self.db.lock().await;
+        let mut mod_list = self.db_modified.lock().await;
+
+        let inner = self.get_submap_or_load(&mut data, group).await?;
+
+        for (key, old) in inner.drain() {
+            mark_modified(&mut mod_list, group, &key);
+            self.adjust_usage(group, Some(entry_size(&key, &old)), None);
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Flush all pending writes to the storage backend. If save data is synced, the changed groups
+     * are then pushed to the sync server. Groups that can't be pushed (e.g. because the server
+     * can't be reached) stay queued for the next flush, so that isn't an error.
+     */
+    pub async fn flush(&self) -> Result<(), Error> {
+        {
+            let mut data = self.db.lock().await;
+            let mut mod_list = self.db_modified.lock().await;
+            self.write_modified(&mut data, &mut mod_list).await?;
+        }
+        self.push_queued().await;
+        Ok(())
+    }
+
+    /**
+     * Write every modified group to the storage backend, queue them to be pushed to the sync
+     * server (if save data is synced) and clear the list of modifications. Nothing is cleared if
+     * any of that fails, so the next flush tries again. The caller has to hold both locks, so
+     * nothing can change in between.
+     */
+    async fn write_modified(
+        &self,
+        data: &mut HashMap<String, GroupData>,
+        mod_list: &mut HashMap<String, ModifiedGroup>,
+    ) -> Result<(), Error> {
+        log::debug!(
+            "Flushing data in db to storage ({} modified groups)",
+            mod_list.len()
+        );
+        let changes = mod_list
+            .iter()
+            .map(|(group, modified)| (group.as_str(), &modified.keys))
+            .collect();
+        self.write_changes(data, changes).await?;
+
+        if let Some(sync) = &self.sync {
+            let changed: Vec<(String, u64)> = mod_list
+                .iter()
+                .map(|(group, modified)| (group.clone(), modified.changed_at))
+                .collect();
+            sync.changed(&changed).await?;
+        }
+        mod_list.clear();
+        Ok(())
+    }
+
+    /**
+     * Write the changed keys of each group to the storage backend. A changed group that isn't
+     * cached is loaded first, so it is never mistaken for an empty group (which would delete it).
+     */
+    async fn write_changes(
+        &self,
+        data: &mut HashMap<String, GroupData>,
+        changes: Vec<(&str, &HashSet<String>)>,
+    ) -> Result<(), Error> {
+        for (group, _) in &changes {
+            self.get_submap_or_load(data, group).await?;
+        }
+        let writes: Vec<GroupWrite> = changes
+            .into_iter()
+            .map(|(group, changed)| GroupWrite {
+                group,
+                values: &data[group],
+                changed,
+            })
+            .collect();
+        self.storage.write_groups(&writes).await
+    }
+
+    /**
+     * Push everything queued to the sync server, if save data is synced. Groups that can't be
+     * pushed stay queued.
+     */
+    async fn push_queued(&self) {
+        if let Some(sync) = &self.sync {
+            if let Err(err) = self.push(sync).await {
+                log::warn!("Couldn't push save data to sync server, will retry: {err}");
+            }
+        }
+    }
+
+    /**
+     * Replace the groups of a game with newer versions from the sync server, if save data is
+     * synced. Groups with changes that haven't been flushed yet are kept, since those changes are
+     * newer than anything on the server.
+     */
+    pub async fn pull(&self, game_id: &str) -> Result<(), Error> {
+        let Some(sync) = &self.sync else {
+            return Ok(());
+        };
+        names::check_game_id(game_id)?;
+        let prefix = format!("{game_id}/");
+        let remote = sync.pull(&prefix).await?;
+
+        let mut data = self.db.lock().await;
+        let mod_list = self.db_modified.lock().await;
+
+        let mut pulled = Vec::new();
+        let mut changed = HashMap::new();
+        for group in remote {
+            if !group.group.starts_with(&prefix) || names::check_group(&group.group).is_err() {
+                log::warn!(
+                    "Ignoring save group '{}' from sync server",
+                    group.group.escape_debug()
+                );
+                continue;
+            }
+            if mod_list.contains_key(&group.group) || !sync.is_newer(&group).await {
+                continue;
+            }
+            log::debug!("Pulled newer version of save group {}", group.group);
+
+            let inner = self.get_submap_or_load(&mut data, &group.group).await?;
+            let keys: HashSet<String> = inner.keys().chain(group.values.keys()).cloned().collect();
+            *inner = group.values;
+            changed.insert(group.group.clone(), keys);
+            pulled.push((group.group, group.updated_at));
+        }
+
+        let changes = changed
+            .iter()
+            .map(|(group, keys)| (group.as_str(), keys))
+            .collect();
+        self.write_changes(&mut data, changes).await?;
+        self.forget_usage(Some(game_id));
+        sync.pulled(&pulled).await
+    }
+
+    /**
+     * Push every queued group to the sync server
+     */
+    async fn push(&self, sync: &SyncClient) -> Result<(), Error> {
+        let pending = sync.pending().await;
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let groups = {
+            let data = self.db.lock().await;
+            let mut groups = Vec::new();
+            for (group, updated_at) in pending {
+                let values = match data.get(&group) {
+                    Some(inner) => inner.clone(),
+                    None => self.storage.load_group(&group).await?,
+                };
+                groups.push(RemoteGroup {
+                    group,
+                    updated_at,
+                    values,
+                });
+            }
+            groups
+        };
+        sync.push(&groups).await
+    }
+
+    /**
+     * Report how much save data every game is storing. Cached groups are counted as they are in
+     * memory, other groups are read from the storage backend without being added to the cache.
+     */
+    pub async fn usage(&self) -> Result<Vec<StorageUsage>, Error> {
+        let data = self.db.lock().await;
+
+        let mut games: HashMap<String, StorageUsage> = HashMap::new();
+        let mut counted = HashSet::new();
+
+        for group in self.storage.list_groups("").await? {
+            let Some(game_id) = game_of(&group.name) else {
+                continue;
+            };
+            let usage = games
+                .entry(game_id.to_string())
+                .or_insert_with(|| StorageUsage {
+                    game_id: game_id.to_string(),
+                    ..Default::default()
+                });
+            usage.disk_bytes += group.bytes;
+
+            if !data.contains_key(&group.name) {
+                match self.storage.load_group(&group.name).await {
+                    Ok(inner) => add_group_usage(usage, &inner),
+                    Err(err) => log::warn!(
+                        "Skipping unreadable group {} in usage report: {err:?}",
+                        group.name
+                    ),
+                }
+                counted.insert(group.name);
+            }
+        }
+
+        for (group, inner) in data.iter() {
+            if counted.contains(group) {
+                continue;
+            }
+            let Some(game_id) = game_of(group) else {
+                continue;
+            };
+            let usage = games
+                .entry(game_id.to_string())
+                .or_insert_with(|| StorageUsage {
+                    game_id: game_id.to_string(),
+                    ..Default::default()
+                });
+            add_group_usage(usage, inner);
+        }
+
+        let mut games: Vec<StorageUsage> = games.into_values().collect();
+        games.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.game_id.cmp(&b.game_id)));
+        Ok(games)
+    }
+
+    /**
+     * Flushes all DB changes, and clears the in-memory cache. This shouldn't need to be done often
+     * but can be done if some games are storing too much data and we need to save memory. I don't
+     * see this actually needing use unless someone is maliciously (or stupidly) trying to store
+     * GBs of data at a time.
+     */
+    pub async fn clear_db(&self) -> Result<(), Error> {
+        log::info!("Flushing and clearing DB cache");
+        // Clear the cache under the same locks as the flush, so a save can't land in between and
+        // be dropped from the cache while it is still waiting to be written
+        {
+            let mut data = self.db.lock().await;
+            let mut mod_list = self.db_modified.lock().await;
+            self.write_modified(&mut data, &mut mod_list).await?;
+            data.clear();
+        }
+        self.push_queued().await;
+        Ok(())
+    }
+
+    /**
+     * Gets the total number of K, V pairs across the entire cache, as a rough proxy for how large
+     * the current cache is.
+     */
+    pub async fn db_cache_size(&self) -> usize {
+        let data = self.db.lock().await;
+        data.values().map(|hm| hm.len()).sum()
+    }
+
+    /**
+     * Store a value in the cache, making sure the game stays within its quota. The caller has to
+     * hold both locks for the whole operation, so that reading a value and then setting it is
+     * atomic.
+     */
+    async fn set(
+        &self,
+        data: &mut HashMap<String, GroupData>,
+        mod_list: &mut HashMap<String, ModifiedGroup>,
+        group: &str,
+        key: &str,
+        value: Value,
+    ) -> Result<(), Error> {
+        let game_id = group.split('/').next().unwrap_or_default();
+
+        let inner = self.get_submap_or_load(data, group).await?;
+        let old_size = inner.get(key).map(|old| entry_size(key, old));
+        self.check_quota(data, game_id, old_size, key, &value)
+            .await?;
+
+        let new_size = entry_size(key, &value);
+        let inner = self.get_submap_or_load(data, group).await?;
+        inner.insert(key.to_string(), value);
+        mark_modified(mod_list, group, key);
+        self.adjust_usage(group, old_size, Some(new_size));
+
+        Ok(())
+    }
+
+    /**
+     * Make sure storing `key` = `value` (replacing an entry of `old_size` bytes, if there is one)
+     * keeps the game within its save data limits. The totals include changes that haven't been
+     * flushed yet.
+     */
+    async fn check_quota(
+        &self,
+        data: &mut HashMap<String, GroupData>,
+        game_id: &str,
+        old_size: Option<u64>,
+        key: &str,
+        value: &Value,
+    ) -> Result<(), Error> {
+        let value_size = entry_size("", value);
+        if let Some(max) = self.quota.max_value_bytes {
+            if value_size > max {
+                return Err(ResponseError::new(
+                    ErrorKind::QuotaExceeded,
+                    format!(
+                        "Value for key {key} is {value_size} bytes, games may store at most {max} bytes per value"
+                    ),
+                )
+                .into());
+            }
+        }
+        if self.quota.max_keys.is_none() && self.quota.max_total_bytes.is_none() {
+            return Ok(());
+        }
+
+        let usage = self.game_usage(data, game_id).await?;
+        let keys = usage.keys + u64::from(old_size.is_none());
+        // Usage can drift below the old entry's size if the files were changed behind our back
+        let bytes =
+            usage.bytes.saturating_sub(old_size.unwrap_or(0)) + key.len() as u64 + value_size;
+
+        if let Some(max) = self.quota.max_keys {
+            if keys > max {
+                return Err(ResponseError::new(
+                    ErrorKind::QuotaExceeded,
+                    format!("Game {game_id} may store at most {max} keys"),
+                )
+                .into());
+            }
+        }
+        if let Some(max) = self.quota.max_total_bytes {
+            if bytes > max {
+                return Err(ResponseError::new(
+                    ErrorKind::QuotaExceeded,
+                    format!(
+                        "Game {game_id} may store at most {max} bytes of save data ({bytes} needed)"
+                    ),
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Get the keys and bytes a game is storing. The first time a game is checked, its groups are
+     * counted up from the cache and the storage backend (without adding them to the cache), after
+     * that the totals are kept up to date by [`Persistence::adjust_usage`].
+     */
+    async fn game_usage(
+        &self,
+        data: &HashMap<String, GroupData>,
+        game_id: &str,
+    ) -> Result<GameUsage, Error> {
+        if let Some(usage) = self.quota_usage.lock().unwrap().get(game_id) {
+            return Ok(*usage);
+        }
+
+        let prefix = format!("{game_id}/");
+        let mut usage = StorageUsage::default();
+        for group in self.storage.list_groups(&prefix).await? {
+            if !data.contains_key(&group.name) {
+                add_group_usage(&mut usage, &self.storage.load_group(&group.name).await?);
+            }
+        }
+        for (_, inner) in data.iter().filter(|(group, _)| group.starts_with(&prefix)) {
+            add_group_usage(&mut usage, inner);
+        }
+
+        let usage = GameUsage {
+            keys: usage.keys,
+            bytes: usage.bytes,
+        };
+        self.quota_usage
+            .lock()
+            .unwrap()
+            .insert(game_id.to_string(), usage);
+        Ok(usage)
+    }
+
+    /**
+     * Update a game's usage totals (if it has been counted up) after an entry of `old` bytes was
+     * replaced with one of `new` bytes. `None` means there was no entry before or after.
+     */
+    fn adjust_usage(&self, group: &str, old: Option<u64>, new: Option<u64>) {
+        let Some(game_id) = game_of(group) else {
+            return;
+        };
+        if let Some(usage) = self.quota_usage.lock().unwrap().get_mut(game_id) {
+            usage.keys =
+                (usage.keys + u64::from(new.is_some())).saturating_sub(u64::from(old.is_some()));
+            usage.bytes = (usage.bytes + new.unwrap_or(0)).saturating_sub(old.unwrap_or(0));
+        }
+    }
+
+    /**
+     * Forget the usage totals of a game (or every game), so they are counted up again the next
+     * time they are needed. Used after changes too large to track one entry at a time.
+     */
+    fn forget_usage(&self, game_id: Option<&str>) {
+        let mut usage = self.quota_usage.lock().unwrap();
+        match game_id {
+            Some(game_id) => {
+                usage.remove(game_id);
+            }
+            None => usage.clear(),
+        }
+    }
+
+    /**
+     * Gets the sub-map for a group, and returns the cached version, the version in the storage
+     * backend, or a new empty HashMap, in order of preference.
+     */
+    async fn get_submap_or_load<'a>(
+        &self,
+        db: &'a mut HashMap<String, GroupData>,
+        group: &str,
+    ) -> Result<&'a mut GroupData, Error> {
+        if !db.contains_key(group) {
+            let map = self.storage.load_group(group).await?;
+            db.insert(group.to_string(), map);
+        }
+        Ok(db.get_mut(group).unwrap())
+    }
+}
+
+/**
+ * Remember that `key` in `group` has to be written on the next flush, and when it was changed
+ */
+fn mark_modified(mod_list: &mut HashMap<String, ModifiedGroup>, group: &str, key: &str) {
+    let modified = mod_list.entry(group.to_string()).or_default();
+    modified.keys.insert(key.to_string());
+    modified.changed_at = modified.changed_at.max(sync::now_millis());
+}
+
+/**
+ * Add the keys and bytes in a group to a game's usage. Empty groups aren't counted, since they are
+ * removed on the next flush.
+ */
+fn add_group_usage(usage: &mut StorageUsage, inner: &GroupData) {
+    if inner.is_empty() {
+        return;
+    }
+    usage.groups += 1;
+    usage.keys += inner.len() as u64;
+    usage.bytes += inner
+        .iter()
+        .map(|(key, value)| entry_size(key, value))
+        .sum::<u64>();
+}
+
+/**
+ * Size of a key and its value in bytes. Strings count as their length, to match how saves were
+ * counted before values could be any JSON. Anything else counts as its length as JSON.
+ */
+fn entry_size(key: &str, value: &Value) -> u64 {
+    let value_size = match value {
+        Value::String(value) => value.len(),
+        value => value.to_string().len(),
+    };
+    (key.len() + value_size) as u64
+}
+
+/**
+ * Get the ID of the game a group belongs to
+ */
+fn game_of(group: &str) -> Option<&str> {
+    group.split_once('/').map(|(game_id, _)| game_id)
+}
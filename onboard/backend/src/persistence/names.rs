@@ -0,0 +1,139 @@
+use anyhow::Error;
+use devcade_onboard_types::error::{ErrorKind, ResponseError};
+
+// Leaves room for the `.save.bak` and `.corrupt-<timestamp>` suffixes within the usual 255 byte
+// file name limit
+const MAX_SEGMENT_LEN: usize = 200;
+
+const MAX_KEY_LEN: usize = 1024;
+
+fn invalid(message: String) -> Error {
+    ResponseError::new(ErrorKind::InvalidRequest, message).into()
+}
+
+/**
+ * Build the full name of a group a game asked for, under `prefix` (a game ID or a player's
+ * directory in it). Group names are the only part of a save path a game controls, so anything
+ * that could point outside of `prefix` is rejected:
+ * - absolute names (`/etc/passwd`)
+ * - `.` and `..` segments, and any other segment starting with a `.`, which are reserved for the
+ *   backend (e.g. player save data)
+ * - control characters, including NUL
+ *
+ * Repeated and trailing `/` are collapsed, so `scores/`, `scores` and `a//b`, `a/b` name the same
+ * groups.
+ */
+pub fn game_group(prefix: &str, group: &str) -> Result<String, Error> {
+    check_prefix(prefix)?;
+    if group.starts_with('/') {
+        return Err(invalid(format!(
+            "Save group '{}' must be relative",
+            group.escape_debug()
+        )));
+    }
+
+    let segments: Vec<&str> = group
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+    if segments.is_empty() {
+        return Err(invalid(String::from("Save group can't be empty")));
+    }
+    for segment in &segments {
+        check_segment(segment, group)?;
+        if segment.starts_with('.') {
+            return Err(invalid(format!(
+                "Save group '{}' can't have a segment starting with '.'",
+                group.escape_debug()
+            )));
+        }
+    }
+    Ok(format!("{prefix}/{}", segments.join("/")))
+}
+
+/**
+ * Make sure a full group name (as built by [`game_group`]) is safe to turn into a path: a game ID
+ * and a group, with no empty, `.` or `..` segments and no control characters.
+ */
+pub fn check_group(group: &str) -> Result<(), Error> {
+    check_prefix(group)?;
+    if !group.contains('/') {
+        return Err(invalid(format!(
+            "Save group '{}' doesn't belong to a game",
+            group.escape_debug()
+        )));
+    }
+    Ok(())
+}
+
+/**
+ * Make sure a group prefix (a game ID, or a directory of groups in it) is safe to list
+ */
+pub fn check_prefix(prefix: &str) -> Result<(), Error> {
+    for segment in prefix.split('/') {
+        if segment.is_empty() {
+            return Err(invalid(format!(
+                "Save group prefix '{}' has an empty segment",
+                prefix.escape_debug()
+            )));
+        }
+        check_segment(segment, prefix)?;
+    }
+    Ok(())
+}
+
+/**
+ * Make sure a game ID is a single safe group segment
+ */
+pub fn check_game_id(game_id: &str) -> Result<(), Error> {
+    check_prefix(game_id)?;
+    if game_id.contains('/') {
+        return Err(invalid(format!(
+            "Game ID '{}' can't contain '/'",
+            game_id.escape_debug()
+        )));
+    }
+    Ok(())
+}
+
+/**
+ * Make sure a key is something a game could reasonably mean. Keys never end up in a path, but
+ * they do end up in logs and export files.
+ */
+pub fn check_key(key: &str) -> Result<(), Error> {
+    if key.len() > MAX_KEY_LEN {
+        return Err(invalid(format!(
+            "Save key is {} bytes, keys can be at most {MAX_KEY_LEN} bytes",
+            key.len()
+        )));
+    }
+    if key.chars().any(char::is_control) {
+        return Err(invalid(format!(
+            "Save key '{}' can't contain control characters",
+            key.escape_debug()
+        )));
+    }
+    Ok(())
+}
+
+fn check_segment(segment: &str, group: &str) -> Result<(), Error> {
+    if segment == "." || segment == ".." {
+        return Err(invalid(format!(
+            "Save group '{}' can't contain '{segment}'",
+            group.escape_debug()
+        )));
+    }
+    if segment.chars().any(char::is_control) {
+        return Err(invalid(format!(
+            "Save group '{}' can't contain control characters",
+            group.escape_debug()
+        )));
+    }
+    if segment.len() > MAX_SEGMENT_LEN {
+        return Err(invalid(format!(
+            "Save group '{}' has a segment longer than {MAX_SEGMENT_LEN} bytes",
+            group.escape_debug()
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,219 @@
+use anyhow::Error;
+use async_trait::async_trait;
+use devcade_onboard_types::error::{ErrorKind, ResponseError};
+use devcade_onboard_types::Value;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::task;
+
+use super::{GroupData, GroupWrite, StorageBackend, StoredGroup};
+use crate::stats::unix_secs;
+
+// Names migrations are recorded under, so each only ever runs once
+const JSON_MIGRATION: &str = "import-json-saves";
+const JSON_VALUES_MIGRATION: &str = "json-values";
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS saves (
+        save_group TEXT NOT NULL,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (save_group, key)
+    );
+    CREATE TABLE IF NOT EXISTS migrations (
+        name TEXT PRIMARY KEY,
+        ran_at INTEGER NOT NULL
+    );
+";
+
+/**
+ * Save data in a single SQLite database with one row per key, holding the value as JSON. Only
+ * changed keys are written on a flush, and every flush is a single transaction.
+ */
+pub struct SqliteStore {
+    // rusqlite is blocking, so every query runs on tokio's blocking thread pool
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    /**
+     * Open (or create) the database at `path`
+     */
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut conn = Connection::open(path)?;
+        // WAL keeps a crash during a flush from corrupting the database, and lets readers through
+        // while a flush is running
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.execute_batch(SCHEMA)?;
+
+        // Values used to be stored as plain strings
+        if !migrated(&conn, JSON_VALUES_MIGRATION)? {
+            let tx = conn.transaction()?;
+            tx.execute("UPDATE saves SET value = json_quote(value)", [])?;
+            mark_migrated(&tx, JSON_VALUES_MIGRATION)?;
+            tx.commit()?;
+        }
+
+        Ok(SqliteStore {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /**
+     * Import every group from an existing store, unless that was already done. The import happens
+     * in one transaction, so if it fails it is retried in full on the next start. The source is
+     * left untouched, so it can be used as a backup.
+     */
+    pub async fn migrate_from(&self, source: &dyn StorageBackend) -> Result<(), Error> {
+        let done = self.query(|conn| migrated(conn, JSON_MIGRATION)).await?;
+        if done {
+            return Ok(());
+        }
+
+        let mut groups = vec![];
+        for group in source.list_groups("").await? {
+            let values = source.load_group(&group.name).await?;
+            groups.push((group.name, values));
+        }
+        log::info!("Importing {} save groups into database", groups.len());
+
+        self.query(move |conn| {
+            let tx = conn.transaction()?;
+            for (group, values) in &groups {
+                for (key, value) in values {
+                    tx.execute(
+                        "INSERT OR REPLACE INTO saves (save_group, key, value) VALUES (?1, ?2, ?3)",
+                        params![group, key, value.to_string()],
+                    )?;
+                }
+            }
+            mark_migrated(&tx, JSON_MIGRATION)?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    /**
+     * Run `f` with the connection on the blocking thread pool
+     */
+    async fn query<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, Error> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        task::spawn_blocking(move || f(&mut conn.lock().unwrap()))
+            .await?
+            .map_err(|err| {
+                ResponseError::new(ErrorKind::Persistence, "Save database query failed")
+                    .with_details(format!("{err:?}"))
+                    .into()
+            })
+    }
+}
+
+#[async_trait]
+impl StorageBackend for SqliteStore {
+    async fn load_group(&self, group: &str) -> Result<GroupData, Error> {
+        let group = group.to_string();
+        self.query(move |conn| {
+            let mut statement =
+                conn.prepare_cached("SELECT key, value FROM saves WHERE save_group = ?1")?;
+            let rows = statement.query_map([group], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?;
+            let mut values = GroupData::new();
+            for row in rows {
+                let (key, value) = row?;
+                values.insert(key, serde_json::from_str(&value)?);
+            }
+            Ok(values)
+        })
+        .await
+    }
+
+    async fn write_groups(&self, writes: &[GroupWrite<'_>]) -> Result<(), Error> {
+        // Only the changed keys are written, `None` deletes the key
+        let changes: Vec<(String, String, Option<String>)> = writes
+            .iter()
+            .flat_map(|write| {
+                write.changed.iter().map(|key| {
+                    (
+                        write.group.to_string(),
+                        key.clone(),
+                        write.values.get(key).map(Value::to_string),
+                    )
+                })
+            })
+            .collect();
+
+        self.query(move |conn| {
+            let tx = conn.transaction()?;
+            for (group, key, value) in &changes {
+                match value {
+                    Some(value) => tx.execute(
+                        "INSERT OR REPLACE INTO saves (save_group, key, value) VALUES (?1, ?2, ?3)",
+                        params![group, key, value],
+                    )?,
+                    None => tx.execute(
+                        "DELETE FROM saves WHERE save_group = ?1 AND key = ?2",
+                        params![group, key],
+                    )?,
+                };
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_groups(&self, prefix: &str) -> Result<Vec<StoredGroup>, Error> {
+        let prefix = prefix.to_string();
+        self.query(move |conn| {
+            // substr instead of LIKE, so games can't smuggle wildcards into the prefix
+            let mut statement = conn.prepare_cached(
+                "SELECT save_group, SUM(LENGTH(CAST(key AS BLOB)) + LENGTH(CAST(value AS BLOB)))
+                 FROM saves
+                 WHERE substr(save_group, 1, length(?1)) = ?1
+                 GROUP BY save_group",
+            )?;
+            let rows = statement.query_map([prefix], |row| {
+                Ok(StoredGroup {
+                    name: row.get(0)?,
+                    bytes: row.get::<_, i64>(1)? as u64,
+                })
+            })?;
+            Ok(rows.collect::<Result<_, _>>()?)
+        })
+        .await
+    }
+}
+
+/**
+ * Whether the migration called `name` has already run
+ */
+fn migrated(conn: &Connection, name: &str) -> Result<bool, Error> {
+    Ok(conn
+        .query_row("SELECT 1 FROM migrations WHERE name = ?1", [name], |_| {
+            Ok(())
+        })
+        .optional()?
+        .is_some())
+}
+
+/**
+ * Record that the migration called `name` has run, as part of the migration's transaction
+ */
+fn mark_migrated(tx: &Transaction, name: &str) -> Result<(), Error> {
+    tx.execute(
+        "INSERT INTO migrations (name, ran_at) VALUES (?1, ?2)",
+        params![name, unix_secs(SystemTime::now())],
+    )?;
+    Ok(())
+}
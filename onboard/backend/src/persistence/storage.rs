@@ -0,0 +1,87 @@
+use anyhow::Error;
+use async_trait::async_trait;
+use devcade_onboard_types::Value;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use super::{JsonStore, SqliteStore};
+
+/**
+ * The keys and values in a single group
+ */
+pub type GroupData = HashMap<String, Value>;
+
+/**
+ * A group as it is stored by a backend
+ */
+#[derive(Debug, Clone)]
+pub struct StoredGroup {
+    /// Full name of the group, starting with the game ID (e.g. `game-id/level-1/scores`)
+    pub name: String,
+    /// How much space the group takes up in the backend, in bytes
+    pub bytes: u64,
+}
+
+/**
+ * Pending changes to a single group, written by [`StorageBackend::write_groups`]
+ */
+pub struct GroupWrite<'a> {
+    /// Full name of the group
+    pub group: &'a str,
+    /// Everything in the group after the changes
+    pub values: &'a GroupData,
+    /// Keys that were set or removed since the group was last written. A key that is missing
+    /// from `values` was removed.
+    pub changed: &'a HashSet<String>,
+}
+
+/**
+ * Somewhere save data is kept between runs of the backend. Reads and writes go through the
+ * in-memory cache in [`super::Persistence`], so a backend only ever sees whole groups being loaded
+ * and batches of changes being flushed.
+ */
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /**
+     * Load everything in a group. A group that was never written is empty.
+     */
+    async fn load_group(&self, group: &str) -> Result<GroupData, Error>;
+
+    /**
+     * Write the changes to every group in `writes`. Groups left empty should be removed.
+     */
+    async fn write_groups(&self, writes: &[GroupWrite<'_>]) -> Result<(), Error>;
+
+    /**
+     * List all non-empty groups whose name starts with `prefix`, which is either empty or ends
+     * with a `/`.
+     */
+    async fn list_groups(&self, prefix: &str) -> Result<Vec<StoredGroup>, Error>;
+}
+
+/**
+ * Open the backend configured by DEVCADE_SAVE_BACKEND, keeping its data in `root`
+ */
+pub async fn from_env(root: &Path) -> Box<dyn StorageBackend> {
+    let json = JsonStore::new(root);
+    match crate::env::save_backend().as_str() {
+        "sqlite" => {
+            let path = root.join("saves.sqlite3");
+            log::info!("Storing save data in SQLite database {path:?}");
+            // Without the database games would see empty saves and overwrite real ones, so
+            // there is nothing sensible to fall back to
+            let store = SqliteStore::open(&path).expect("Couldn't open save database");
+            if let Err(err) = store.migrate_from(&json).await {
+                panic!("Couldn't import .save files into save database: {err:?}");
+            }
+            Box::new(store)
+        }
+        backend => {
+            if backend != "json" {
+                log::warn!("Unknown save backend '{backend}', falling back to json");
+            }
+            log::info!("Storing save data as JSON files in {root:?}");
+            Box::new(json)
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use anyhow::Error;
+use devcade_onboard_types::error::{ErrorKind, ResponseError};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+
+use super::json::write_save_file;
+use super::GroupData;
+
+/**
+ * A group as it is sent to and received from the sync server
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RemoteGroup {
+    /// Full name of the group, starting with the game ID
+    pub group: String,
+    /// When the group was last changed, in milliseconds since the unix epoch
+    pub updated_at: u64,
+    /// Everything in the group. Empty if the group was cleared.
+    pub values: GroupData,
+}
+
+/**
+ * What the sync client knows about local groups. This is kept on disk, so that changes made
+ * while the server can't be reached are still pushed after a restart.
+ */
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SyncState {
+    // When each group was last changed, locally or by a pull
+    updated: HashMap<String, u64>,
+    // Groups changed locally that the server doesn't have yet
+    pending: BTreeSet<String>,
+}
+
+/**
+ * Pushes local changes to a sync server and pulls changes other cabinets made. Conflicts are
+ * resolved by keeping whichever version of a group was changed last, so the cabinets' clocks
+ * should be kept in sync.
+ */
+pub struct SyncClient {
+    url: String,
+    token: Option<String>,
+    client: reqwest::Client,
+    state_path: PathBuf,
+    state: Mutex<SyncState>,
+}
+
+impl SyncClient {
+    /**
+     * Create a client for the server at `url`, keeping its queue of unpushed groups in
+     * `state_path`.
+     */
+    pub fn new(
+        url: impl Into<String>,
+        token: Option<String>,
+        state_path: impl Into<PathBuf>,
+    ) -> Self {
+        let state_path = state_path.into();
+        let state = match std::fs::read_to_string(&state_path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|err| {
+                log::warn!("Ignoring unreadable save sync state {state_path:?}: {err}");
+                SyncState::default()
+            }),
+            Err(_) => SyncState::default(),
+        };
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .unwrap_or_default();
+
+        SyncClient {
+            url: url.into(),
+            token,
+            client,
+            state_path,
+            state: Mutex::new(state),
+        }
+    }
+
+    /**
+     * Record that `groups` were changed locally at the given times (in milliseconds since the
+     * unix epoch), queueing them to be pushed
+     */
+    pub async fn changed(&self, groups: &[(String, u64)]) -> Result<(), Error> {
+        if groups.is_empty() {
+            return Ok(());
+        }
+        let mut state = self.state.lock().await;
+        for (group, changed_at) in groups {
+            // Never go back in time, even if the clock does
+            let updated = state
+                .updated
+                .get(group)
+                .map_or(*changed_at, |last| (*changed_at).max(last + 1));
+            state.updated.insert(group.clone(), updated);
+            state.pending.insert(group.clone());
+        }
+        self.save(&state).await
+    }
+
+    /**
+     * Get the groups waiting to be pushed, with when they were changed
+     */
+    pub async fn pending(&self) -> Vec<(String, u64)> {
+        let state = self.state.lock().await;
+        state
+            .pending
+            .iter()
+            .map(|group| {
+                (
+                    group.clone(),
+                    state.updated.get(group).copied().unwrap_or(0),
+                )
+            })
+            .collect()
+    }
+
+    /**
+     * Whether `remote` is newer than the local version of its group
+     */
+    pub async fn is_newer(&self, remote: &RemoteGroup) -> bool {
+        let state = self.state.lock().await;
+        remote.updated_at > state.updated.get(&remote.group).copied().unwrap_or(0)
+    }
+
+    /**
+     * Send groups to the server. Groups that were changed again since they were read stay queued.
+     */
+    pub async fn push(&self, groups: &[RemoteGroup]) -> Result<(), Error> {
+        log::debug!("Pushing {} save groups to {}", groups.len(), self.url);
+        let request = self
+            .client
+            .post(format!("{}/groups", self.url))
+            .json(groups);
+        let response = self.authorize(request).send().await.map_err(offline)?;
+        if !response.status().is_success() {
+            return Err(ResponseError::new(
+                ErrorKind::ApiError,
+                format!("Save sync server answered {}", response.status()),
+            )
+            .into());
+        }
+
+        let mut state = self.state.lock().await;
+        for group in groups {
+            if state.updated.get(&group.group) == Some(&group.updated_at) {
+                state.pending.remove(&group.group);
+            }
+        }
+        self.save(&state).await
+    }
+
+    /**
+     * Get the server's latest version of every group starting with `prefix`
+     */
+    pub async fn pull(&self, prefix: &str) -> Result<Vec<RemoteGroup>, Error> {
+        log::debug!("Pulling save groups in {prefix} from {}", self.url);
+        let request = self
+            .client
+            .get(format!("{}/groups", self.url))
+            .query(&[("prefix", prefix)]);
+        let response = self.authorize(request).send().await.map_err(offline)?;
+        if !response.status().is_success() {
+            return Err(ResponseError::new(
+                ErrorKind::ApiError,
+                format!("Save sync server answered {}", response.status()),
+            )
+            .into());
+        }
+        response.json().await.map_err(|err| {
+            ResponseError::new(
+                ErrorKind::ApiError,
+                "Couldn't read the response from the save sync server",
+            )
+            .with_details(err)
+            .into()
+        })
+    }
+
+    /**
+     * Record that pulled groups replaced the local versions. Local changes to them that weren't
+     * pushed yet lost to the newer remote version, so they are dropped from the queue.
+     */
+    pub async fn pulled(&self, groups: &[(String, u64)]) -> Result<(), Error> {
+        if groups.is_empty() {
+            return Ok(());
+        }
+        let mut state = self.state.lock().await;
+        for (group, updated_at) in groups {
+            state.updated.insert(group.clone(), *updated_at);
+            state.pending.remove(group);
+        }
+        self.save(&state).await
+    }
+
+    fn authorize(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    async fn save(&self, state: &SyncState) -> Result<(), Error> {
+        if let Some(dir) = self.state_path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        write_save_file(&self.state_path, &serde_json::to_vec(state)?).await
+    }
+}
+
+/**
+ * Error for a request that never got an answer from the sync server
+ */
+fn offline(err: reqwest::Error) -> Error {
+    ResponseError::new(ErrorKind::ApiOffline, "Couldn't reach the save sync server")
+        .with_details(err)
+        .into()
+}
+
+/**
+ * Milliseconds since the unix epoch
+ */
+pub(super) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_millis() as u64)
+        .unwrap_or_default()
+}
@@ -0,0 +1,912 @@
+use super::*;
+use devcade_onboard_types::error::ResponseError;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server};
+use std::convert::Infallible;
+use std::sync::Arc;
+use tempfile::TempDir;
+
+const UNLIMITED: SaveQuota = SaveQuota {
+    max_keys: None,
+    max_value_bytes: None,
+    max_total_bytes: None,
+};
+
+fn json_store(dir: &TempDir) -> Persistence {
+    Persistence::new(Box::new(JsonStore::new(dir.path())), UNLIMITED)
+}
+
+fn sqlite_store(dir: &TempDir) -> Persistence {
+    let store = SqliteStore::open(&dir.path().join("saves.sqlite3")).unwrap();
+    Persistence::new(Box::new(store), UNLIMITED)
+}
+
+fn error_kind(err: Error) -> ErrorKind {
+    err.downcast::<ResponseError>().unwrap().kind
+}
+
+type RemoteGroups = Arc<std::sync::Mutex<HashMap<String, RemoteGroup>>>;
+
+/**
+ * Start a sync server that keeps the newest version of every group, like a real one would
+ */
+async fn mock_sync_server() -> (String, RemoteGroups) {
+    let groups = RemoteGroups::default();
+    let state = groups.clone();
+    let service = make_service_fn(move |_| {
+        let state = state.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| sync_request(state.clone(), req))) }
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(service);
+    let url = format!("http://{}", server.local_addr());
+    tokio::spawn(server);
+    (url, groups)
+}
+
+async fn sync_request(
+    groups: RemoteGroups,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let url = reqwest::Url::parse(&format!("http://localhost{}", req.uri())).unwrap();
+    match (req.method().clone(), url.path()) {
+        (Method::POST, "/groups") => {
+            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+            let pushed: Vec<RemoteGroup> = serde_json::from_slice(&body).unwrap();
+            let mut groups = groups.lock().unwrap();
+            for group in pushed {
+                if !matches!(groups.get(&group.group), Some(old) if old.updated_at >= group.updated_at)
+                {
+                    groups.insert(group.group.clone(), group);
+                }
+            }
+            Ok(Response::new(Body::empty()))
+        }
+        (Method::GET, "/groups") => {
+            let (_, prefix) = url
+                .query_pairs()
+                .find(|(name, _)| name == "prefix")
+                .unwrap();
+            let groups: Vec<RemoteGroup> = groups
+                .lock()
+                .unwrap()
+                .values()
+                .filter(|group| group.group.starts_with(prefix.as_ref()))
+                .cloned()
+                .collect();
+            Ok(Response::new(Body::from(
+                serde_json::to_vec(&groups).unwrap(),
+            )))
+        }
+        _ => Ok(Response::builder().status(404).body(Body::empty()).unwrap()),
+    }
+}
+
+fn synced_store(dir: &TempDir, url: &str) -> Persistence {
+    let sync = SyncClient::new(url, None, dir.path().join("sync-state.json"));
+    json_store(dir).with_sync(sync)
+}
+
+#[tokio::test]
+async fn load_returns_saved_value() {
+    let dir = TempDir::new().unwrap();
+    let persistence = json_store(&dir);
+
+    persistence
+        .save("game/scores", "high", "100")
+        .await
+        .unwrap();
+    persistence
+        .save("game/scores", "high", "200")
+        .await
+        .unwrap();
+
+    assert_eq!(
+        persistence.load("game/scores", "high").await.unwrap(),
+        "200"
+    );
+}
+
+#[tokio::test]
+async fn load_missing_key_is_key_not_found() {
+    let dir = TempDir::new().unwrap();
+    let persistence = json_store(&dir);
+
+    persistence
+        .save("game/scores", "high", "100")
+        .await
+        .unwrap();
+
+    let err = persistence.load("game/scores", "low").await.unwrap_err();
+    assert_eq!(error_kind(err), ErrorKind::KeyNotFound);
+    let err = persistence.load("game/other", "high").await.unwrap_err();
+    assert_eq!(error_kind(err), ErrorKind::KeyNotFound);
+}
+
+#[tokio::test]
+async fn flush_writes_groups_to_disk() {
+    let dir = TempDir::new().unwrap();
+    let persistence = json_store(&dir);
+
+    persistence
+        .save("game/level-1/scores", "high", "100")
+        .await
+        .unwrap();
+    assert!(!dir.path().join("game/level-1/scores.save").exists());
+
+    persistence.flush().await.unwrap();
+
+    let file = std::fs::read_to_string(dir.path().join("game/level-1/scores.save")).unwrap();
+    let saved: HashMap<String, String> = serde_json::from_str(&file).unwrap();
+    assert_eq!(saved, HashMap::from([("high".into(), "100".into())]));
+}
+
+#[tokio::test]
+async fn reload_from_disk_after_flush() {
+    let dir = TempDir::new().unwrap();
+    {
+        let persistence = json_store(&dir);
+        persistence
+            .save("game/scores", "high", "100")
+            .await
+            .unwrap();
+        persistence.save("game/scores", "low", "1").await.unwrap();
+        persistence
+            .save("game/options", "volume", "11")
+            .await
+            .unwrap();
+        persistence.flush().await.unwrap();
+    }
+
+    let persistence = json_store(&dir);
+    assert_eq!(
+        persistence.load("game/scores", "high").await.unwrap(),
+        "100"
+    );
+    assert_eq!(persistence.load("game/scores", "low").await.unwrap(), "1");
+    assert_eq!(
+        persistence.load("game/options", "volume").await.unwrap(),
+        "11"
+    );
+    assert_eq!(
+        persistence.list_groups("game").await.unwrap(),
+        vec!["options", "scores"]
+    );
+}
+
+#[tokio::test]
+async fn unflushed_changes_are_not_on_disk() {
+    let dir = TempDir::new().unwrap();
+    {
+        let persistence = json_store(&dir);
+        persistence
+            .save("game/scores", "high", "100")
+            .await
+            .unwrap();
+        persistence.flush().await.unwrap();
+        persistence
+            .save("game/scores", "high", "200")
+            .await
+            .unwrap();
+    }
+
+    let persistence = json_store(&dir);
+    assert_eq!(
+        persistence.load("game/scores", "high").await.unwrap(),
+        "100"
+    );
+}
+
+#[tokio::test]
+async fn deletes_and_clears_survive_reload() {
+    let dir = TempDir::new().unwrap();
+    {
+        let persistence = json_store(&dir);
+        persistence
+            .save("game/scores", "high", "100")
+            .await
+            .unwrap();
+        persistence.save("game/scores", "low", "1").await.unwrap();
+        persistence
+            .save("game/options", "volume", "11")
+            .await
+            .unwrap();
+        persistence.flush().await.unwrap();
+
+        persistence.delete("game/scores", "low").await.unwrap();
+        persistence.clear_group("game/options").await.unwrap();
+        persistence.flush().await.unwrap();
+    }
+    assert!(!dir.path().join("game/options.save").exists());
+
+    let persistence = json_store(&dir);
+    assert_eq!(
+        persistence.list_keys("game/scores").await.unwrap(),
+        vec!["high"]
+    );
+    assert_eq!(
+        persistence.list_groups("game").await.unwrap(),
+        vec!["scores"]
+    );
+}
+
+#[tokio::test]
+async fn flushing_a_group_that_is_not_cached_keeps_it() {
+    let dir = TempDir::new().unwrap();
+    let persistence = json_store(&dir);
+    persistence
+        .save("game/scores", "high", "100")
+        .await
+        .unwrap();
+    persistence.clear_db().await.unwrap();
+
+    // A change recorded for a group that was dropped from the cache, as if a save had raced the
+    // cache being cleared
+    mark_modified(
+        &mut *persistence.db_modified.lock().await,
+        "game/scores",
+        "low",
+    );
+    persistence.flush().await.unwrap();
+
+    let persistence = json_store(&dir);
+    assert_eq!(
+        persistence.load("game/scores", "high").await.unwrap(),
+        "100"
+    );
+}
+
+#[tokio::test]
+async fn sqlite_reload_after_flush() {
+    let dir = TempDir::new().unwrap();
+    {
+        let persistence = sqlite_store(&dir);
+        persistence
+            .save("game/scores", "high", "100")
+            .await
+            .unwrap();
+        persistence.save("game/scores", "low", "1").await.unwrap();
+        persistence.flush().await.unwrap();
+        persistence.delete("game/scores", "low").await.unwrap();
+        persistence.flush().await.unwrap();
+    }
+
+    let persistence = sqlite_store(&dir);
+    assert_eq!(
+        persistence.load("game/scores", "high").await.unwrap(),
+        "100"
+    );
+    assert_eq!(
+        persistence.list_keys("game/scores").await.unwrap(),
+        vec!["high"]
+    );
+}
+
+#[tokio::test]
+async fn sqlite_imports_json_saves_once() {
+    let dir = TempDir::new().unwrap();
+    {
+        let persistence = json_store(&dir);
+        persistence
+            .save("game/scores", "high", "100")
+            .await
+            .unwrap();
+        persistence.flush().await.unwrap();
+    }
+
+    let json = JsonStore::new(dir.path());
+    let sqlite = SqliteStore::open(&dir.path().join("saves.sqlite3")).unwrap();
+    sqlite.migrate_from(&json).await.unwrap();
+    let persistence = Persistence::new(Box::new(sqlite), UNLIMITED);
+    assert_eq!(
+        persistence.load("game/scores", "high").await.unwrap(),
+        "100"
+    );
+    persistence.delete("game/scores", "high").await.unwrap();
+    persistence.flush().await.unwrap();
+
+    // Starting up again must not bring back data from the old files
+    let sqlite = SqliteStore::open(&dir.path().join("saves.sqlite3")).unwrap();
+    sqlite.migrate_from(&json).await.unwrap();
+    let persistence = Persistence::new(Box::new(sqlite), UNLIMITED);
+    let err = persistence.load("game/scores", "high").await.unwrap_err();
+    assert_eq!(error_kind(err), ErrorKind::KeyNotFound);
+}
+
+#[test]
+fn game_groups_are_normalized() {
+    assert_eq!(game_group("game", "scores").unwrap(), "game/scores");
+    assert_eq!(game_group("game", "scores/").unwrap(), "game/scores");
+    assert_eq!(
+        game_group("game", "level-1//scores").unwrap(),
+        "game/level-1/scores"
+    );
+    assert_eq!(
+        game_group("game/.players/abc", "scores").unwrap(),
+        "game/.players/abc/scores"
+    );
+}
+
+#[test]
+fn game_groups_cannot_escape_the_game() {
+    for group in [
+        "../other-game/scores",
+        "scores/../../other-game/scores",
+        "..",
+        ".",
+        "./scores",
+        "/etc/passwd",
+        "/scores",
+        "",
+        "/",
+        "scores\0",
+        "sco\nres",
+        ".players/abc/scores",
+        ".hidden",
+    ] {
+        let err = game_group("game", group).unwrap_err();
+        assert_eq!(error_kind(err), ErrorKind::InvalidRequest, "{group:?}");
+    }
+    // Games must have an ID to save anything
+    assert!(game_group("", "scores").is_err());
+}
+
+#[tokio::test]
+async fn unsafe_names_are_rejected_by_the_store() {
+    let dir = TempDir::new().unwrap();
+    let persistence = json_store(&dir);
+
+    for group in [
+        "game/../other",
+        "/etc/passwd",
+        "game//scores",
+        "scores",
+        "game/\u{7}",
+    ] {
+        let err = persistence.save(group, "key", "value").await.unwrap_err();
+        assert_eq!(error_kind(err), ErrorKind::InvalidRequest, "{group:?}");
+    }
+    let err = persistence
+        .save("game/scores", "a\nb", "value")
+        .await
+        .unwrap_err();
+    assert_eq!(error_kind(err), ErrorKind::InvalidRequest);
+    assert!(persistence.list_groups("../game").await.is_err());
+}
+
+#[tokio::test]
+async fn games_only_see_their_own_groups() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().join("saves");
+    let persistence = Persistence::new(Box::new(JsonStore::new(&root)), UNLIMITED);
+
+    let group = game_group("game-a", "scores").unwrap();
+    persistence.save(&group, "high", "100").await.unwrap();
+    assert!(game_group("game-b", "../game-a/scores").is_err());
+    let group = game_group("game-b", "game-a/scores").unwrap();
+    persistence.save(&group, "high", "1").await.unwrap();
+    persistence.flush().await.unwrap();
+
+    assert_eq!(
+        persistence.load("game-a/scores", "high").await.unwrap(),
+        "100"
+    );
+    assert_eq!(
+        persistence.list_groups("game-a").await.unwrap(),
+        vec!["scores"]
+    );
+    assert_eq!(
+        persistence.list_groups("game-b").await.unwrap(),
+        vec!["game-a/scores"]
+    );
+    // Nothing was written outside of the save root
+    let mut outside: Vec<_> = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    outside.sort();
+    assert_eq!(outside, vec!["saves"]);
+}
+
+#[tokio::test]
+async fn quotas_follow_saves_and_deletes() {
+    let dir = TempDir::new().unwrap();
+    let quota = SaveQuota {
+        max_keys: Some(3),
+        max_value_bytes: None,
+        max_total_bytes: Some(21),
+    };
+    let persistence = Persistence::new(Box::new(JsonStore::new(dir.path())), quota);
+    persistence.save("game/a", "k1", "12345").await.unwrap();
+    persistence.save("game/b", "k2", "12345").await.unwrap();
+    persistence.flush().await.unwrap();
+
+    // A fresh store counts what is already on disk
+    let persistence = Persistence::new(Box::new(JsonStore::new(dir.path())), quota);
+    let err = persistence
+        .save("game/c", "k3", "123456")
+        .await
+        .unwrap_err();
+    assert_eq!(error_kind(err), ErrorKind::QuotaExceeded);
+    persistence.save("game/c", "k3", "12345").await.unwrap();
+    let err = persistence.save("game/c", "k4", "").await.unwrap_err();
+    assert_eq!(error_kind(err), ErrorKind::QuotaExceeded);
+    // Replacing a value only counts the difference
+    persistence.save("game/c", "k3", "1").await.unwrap();
+
+    // Deleted and cleared entries free up space
+    persistence.delete("game/a", "k1").await.unwrap();
+    persistence.clear_group("game/b").await.unwrap();
+    persistence.save("game/d", "k5", "12345").await.unwrap();
+    persistence.save("game/d", "k6", "12345").await.unwrap();
+    // Other games have their own quota
+    persistence.save("other/a", "k1", "12345").await.unwrap();
+}
+
+#[tokio::test]
+async fn typed_values_survive_reload() {
+    let dir = TempDir::new().unwrap();
+    let scores = serde_json::json!([{ "name": "abc", "score": 100 }]);
+    for store in [json_store, sqlite_store] {
+        {
+            let persistence = store(&dir);
+            persistence
+                .save("game/scores", "table", scores.clone())
+                .await
+                .unwrap();
+            persistence
+                .save("game/scores", "blob", Bytes(vec![0, 159, 255]).to_base64())
+                .await
+                .unwrap();
+            persistence.flush().await.unwrap();
+        }
+
+        let persistence = store(&dir);
+        assert_eq!(
+            persistence.load("game/scores", "table").await.unwrap(),
+            scores
+        );
+        assert_eq!(
+            persistence.load_bytes("game/scores", "blob").await.unwrap(),
+            Bytes(vec![0, 159, 255])
+        );
+        let err = persistence
+            .load_bytes("game/scores", "table")
+            .await
+            .unwrap_err();
+        assert_eq!(error_kind(err), ErrorKind::InvalidRequest);
+    }
+}
+
+#[tokio::test]
+async fn increments_do_not_race() {
+    let dir = TempDir::new().unwrap();
+    let persistence = json_store(&dir);
+
+    let increments = (0..50).map(|_| persistence.increment("game/stats", "plays", 2));
+    futures_util::future::try_join_all(increments)
+        .await
+        .unwrap();
+
+    assert_eq!(persistence.load("game/stats", "plays").await.unwrap(), 100);
+    persistence.save("game/stats", "name", "abc").await.unwrap();
+    let err = persistence
+        .increment("game/stats", "name", 1)
+        .await
+        .unwrap_err();
+    assert_eq!(error_kind(err), ErrorKind::InvalidRequest);
+    persistence
+        .save("game/stats", "max", i64::MAX)
+        .await
+        .unwrap();
+    let err = persistence
+        .increment("game/stats", "max", 1)
+        .await
+        .unwrap_err();
+    assert_eq!(error_kind(err), ErrorKind::InvalidRequest);
+}
+
+#[tokio::test]
+async fn compare_and_swap_only_replaces_expected_values() {
+    let dir = TempDir::new().unwrap();
+    let persistence = json_store(&dir);
+
+    let result = persistence
+        .compare_and_swap("game/scores", "high", None, Value::from(100))
+        .await
+        .unwrap();
+    assert_eq!(
+        result,
+        SwapResult {
+            swapped: true,
+            current: Some(Value::from(100))
+        }
+    );
+
+    // Someone else got there first
+    let result = persistence
+        .compare_and_swap("game/scores", "high", None, Value::from(50))
+        .await
+        .unwrap();
+    assert_eq!(
+        result,
+        SwapResult {
+            swapped: false,
+            current: Some(Value::from(100))
+        }
+    );
+
+    let result = persistence
+        .compare_and_swap(
+            "game/scores",
+            "high",
+            Some(Value::from(100)),
+            Value::from(200),
+        )
+        .await
+        .unwrap();
+    assert!(result.swapped);
+    assert_eq!(persistence.load("game/scores", "high").await.unwrap(), 200);
+}
+
+#[tokio::test]
+async fn sqlite_string_values_are_migrated() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("saves.sqlite3");
+    {
+        // A database from before values were stored as JSON
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE saves (
+                 save_group TEXT NOT NULL,
+                 key TEXT NOT NULL,
+                 value TEXT NOT NULL,
+                 PRIMARY KEY (save_group, key)
+             );
+             INSERT INTO saves VALUES ('game/scores', 'high', '100');",
+        )
+        .unwrap();
+    }
+
+    let persistence = sqlite_store(&dir);
+    assert_eq!(
+        persistence.load("game/scores", "high").await.unwrap(),
+        Value::from("100")
+    );
+    drop(persistence);
+
+    // And only once
+    let persistence = sqlite_store(&dir);
+    assert_eq!(
+        persistence.load("game/scores", "high").await.unwrap(),
+        Value::from("100")
+    );
+}
+
+#[tokio::test]
+async fn export_includes_unflushed_changes_of_one_game() {
+    let dir = TempDir::new().unwrap();
+    let persistence = json_store(&dir);
+
+    persistence.save("game/scores", "high", 100).await.unwrap();
+    persistence.flush().await.unwrap();
+    persistence.save("game/scores", "low", 1).await.unwrap();
+    persistence
+        .save("game/.players/abc/progress", "level", 3)
+        .await
+        .unwrap();
+    persistence.save("other/scores", "high", 5).await.unwrap();
+
+    let archive = persistence.export(Some("game")).await.unwrap();
+    assert_eq!(archive.manifest.game_id.as_deref(), Some("game"));
+    assert_eq!(archive.manifest.groups, 2);
+    assert_eq!(archive.manifest.keys, 3);
+    assert_eq!(
+        archive.groups.keys().collect::<Vec<_>>(),
+        ["game/.players/abc/progress", "game/scores"]
+    );
+    assert_eq!(archive.groups["game/scores"]["low"], 1);
+
+    let archive = persistence.export(None).await.unwrap();
+    assert_eq!(archive.manifest.game_id, None);
+    assert_eq!(archive.manifest.groups, 3);
+}
+
+#[tokio::test]
+async fn import_merges_or_replaces() {
+    let dir = TempDir::new().unwrap();
+    let source = json_store(&dir);
+    source.save("game/scores", "high", 100).await.unwrap();
+    let archive = source.export(Some("game")).await.unwrap();
+
+    let dir = TempDir::new().unwrap();
+    let persistence = sqlite_store(&dir);
+    persistence.save("game/scores", "low", 1).await.unwrap();
+    persistence.save("game/other", "key", 2).await.unwrap();
+    persistence.save("keep/scores", "high", 3).await.unwrap();
+
+    let summary = persistence
+        .import(&archive, ImportMode::Merge)
+        .await
+        .unwrap();
+    assert_eq!(
+        (summary.groups, summary.keys, summary.removed_groups),
+        (1, 1, 0)
+    );
+    assert_eq!(
+        persistence.list_keys("game/scores").await.unwrap(),
+        ["high", "low"]
+    );
+
+    let summary = persistence
+        .import(&archive, ImportMode::Replace)
+        .await
+        .unwrap();
+    assert_eq!(summary.removed_groups, 2);
+
+    // Imports are flushed, so a fresh store sees them
+    let reloaded = sqlite_store(&dir);
+    assert_eq!(reloaded.list_groups("game").await.unwrap(), ["scores"]);
+    assert_eq!(reloaded.list_keys("game/scores").await.unwrap(), ["high"]);
+    assert_eq!(reloaded.load("keep/scores", "high").await.unwrap(), 3);
+}
+
+#[tokio::test]
+async fn empty_archives_only_replace_everything_when_forced() {
+    let dir = TempDir::new().unwrap();
+    let persistence = json_store(&dir);
+    persistence.save("game/scores", "high", 100).await.unwrap();
+    let mut empty = persistence.export(None).await.unwrap();
+    empty.groups.clear();
+    empty.manifest.groups = 0;
+    empty.manifest.keys = 0;
+
+    let err = persistence
+        .import(&empty, ImportMode::Replace)
+        .await
+        .unwrap_err();
+    assert_eq!(error_kind(err), ErrorKind::InvalidRequest);
+    assert_eq!(persistence.load("game/scores", "high").await.unwrap(), 100);
+
+    let summary = persistence
+        .import(&empty, ImportMode::ForceReplace)
+        .await
+        .unwrap();
+    assert_eq!(summary.removed_groups, 1);
+    assert!(persistence.list_groups("game").await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn malformed_archives_are_rejected() {
+    let dir = TempDir::new().unwrap();
+    let persistence = json_store(&dir);
+    persistence.save("game/scores", "high", 100).await.unwrap();
+    let archive = persistence.export(Some("game")).await.unwrap();
+
+    let mut wrong_format = archive.clone();
+    wrong_format.manifest.format = String::from("zip");
+    let mut newer = archive.clone();
+    newer.manifest.version += 1;
+    let mut wrong_counts = archive.clone();
+    wrong_counts.manifest.keys += 1;
+    let mut other_game = archive.clone();
+    other_game
+        .groups
+        .insert(String::from("other/scores"), Default::default());
+    other_game.manifest.groups += 1;
+    let mut escape = archive.clone();
+    let scores = escape.groups.remove("game/scores").unwrap();
+    escape.groups.insert(String::from("game/../../etc"), scores);
+
+    for archive in [wrong_format, newer, wrong_counts, other_game, escape] {
+        let err = persistence
+            .import(&archive, ImportMode::Replace)
+            .await
+            .unwrap_err();
+        assert_eq!(error_kind(err), ErrorKind::InvalidRequest);
+    }
+    // Nothing was removed by the rejected imports
+    assert_eq!(persistence.load("game/scores", "high").await.unwrap(), 100);
+
+    // Unknown fields aren't silently dropped
+    let mut json = serde_json::to_value(&archive).unwrap();
+    json["manifest"]["extra"] = Value::from(1);
+    assert!(serde_json::from_value::<SaveArchive>(json).is_err());
+}
+
+#[tokio::test]
+async fn sync_shares_saves_between_cabinets() {
+    let (url, _) = mock_sync_server().await;
+    let first_dir = TempDir::new().unwrap();
+    let first = synced_store(&first_dir, &url);
+    let second_dir = TempDir::new().unwrap();
+    let second = synced_store(&second_dir, &url);
+
+    first.save("game/scores", "high", 100).await.unwrap();
+    first.flush().await.unwrap();
+    second.pull("game").await.unwrap();
+    assert_eq!(second.load("game/scores", "high").await.unwrap(), 100);
+
+    second.save("game/scores", "high", 200).await.unwrap();
+    second.flush().await.unwrap();
+    first.pull("game").await.unwrap();
+    assert_eq!(first.load("game/scores", "high").await.unwrap(), 200);
+
+    // Pulled groups are written to storage right away
+    assert_eq!(
+        json_store(&first_dir)
+            .load("game/scores", "high")
+            .await
+            .unwrap(),
+        200
+    );
+}
+
+#[tokio::test]
+async fn sync_keeps_the_last_written_group() {
+    let (url, remote) = mock_sync_server().await;
+    let dir = TempDir::new().unwrap();
+    let persistence = synced_store(&dir, &url);
+
+    persistence.save("game/scores", "high", 100).await.unwrap();
+    persistence.flush().await.unwrap();
+
+    let mut values = GroupData::new();
+    values.insert(String::from("high"), Value::from(50));
+    let older = RemoteGroup {
+        group: String::from("game/scores"),
+        updated_at: 1,
+        values,
+    };
+    remote
+        .lock()
+        .unwrap()
+        .insert(older.group.clone(), older.clone());
+    persistence.pull("game").await.unwrap();
+    assert_eq!(persistence.load("game/scores", "high").await.unwrap(), 100);
+
+    let newer = RemoteGroup {
+        updated_at: u64::MAX,
+        values: GroupData::new(),
+        ..older
+    };
+    remote.lock().unwrap().insert(newer.group.clone(), newer);
+    persistence.pull("game").await.unwrap();
+    assert!(persistence.list_groups("game").await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn sync_queues_changes_while_offline() {
+    // Nothing listens on a port that was just freed
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let offline_url = format!("http://{}", listener.local_addr().unwrap());
+    drop(listener);
+
+    let dir = TempDir::new().unwrap();
+    let persistence = synced_store(&dir, &offline_url);
+    persistence.save("game/scores", "high", 100).await.unwrap();
+    persistence.flush().await.unwrap();
+    assert!(persistence.pull("game").await.is_err());
+    drop(persistence);
+
+    // The queue survives a restart, and is pushed on the next flush
+    let (url, remote) = mock_sync_server().await;
+    let persistence = synced_store(&dir, &url);
+    persistence.flush().await.unwrap();
+    assert_eq!(remote.lock().unwrap()["game/scores"].values["high"], 100);
+}
+
+#[tokio::test]
+async fn synced_groups_carry_when_they_were_saved() {
+    let (url, remote) = mock_sync_server().await;
+    let dir = TempDir::new().unwrap();
+    let persistence = synced_store(&dir, &url);
+
+    persistence.save("game/scores", "high", 100).await.unwrap();
+    let saved_by = sync::now_millis();
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    persistence.flush().await.unwrap();
+
+    assert!(remote.lock().unwrap()["game/scores"].updated_at <= saved_by);
+}
+
+#[tokio::test]
+async fn groups_stay_queued_when_the_sync_state_cannot_be_written() {
+    let (url, remote) = mock_sync_server().await;
+    let dir = TempDir::new().unwrap();
+    // A file where the state's directory should be makes writing the state fail
+    let blocker = dir.path().join("blocker");
+    std::fs::write(&blocker, "").unwrap();
+    let sync = SyncClient::new(&url, None, blocker.join("sync-state.json"));
+    let persistence = json_store(&dir).with_sync(sync);
+
+    persistence.save("game/scores", "high", 100).await.unwrap();
+    assert!(persistence.flush().await.is_err());
+    assert!(remote.lock().unwrap().is_empty());
+
+    std::fs::remove_file(&blocker).unwrap();
+    persistence.flush().await.unwrap();
+    assert_eq!(remote.lock().unwrap()["game/scores"].values["high"], 100);
+}
+
+/**
+ * Names of the files in `dir` that were moved out of the way for being corrupt
+ */
+fn quarantined(dir: &Path) -> Vec<String> {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .filter(|name| name.contains(".corrupt-"))
+        .collect()
+}
+
+#[tokio::test]
+async fn truncated_saves_are_recovered_from_the_backup() {
+    let dir = TempDir::new().unwrap();
+    {
+        let persistence = json_store(&dir);
+        persistence.save("game/scores", "high", 100).await.unwrap();
+        persistence.flush().await.unwrap();
+        persistence.save("game/scores", "high", 200).await.unwrap();
+        persistence.flush().await.unwrap();
+    }
+    let save = dir.path().join("game/scores.save");
+    std::fs::write(&save, r#"{"high":2"#).unwrap();
+
+    let persistence = json_store(&dir);
+    assert_eq!(persistence.load("game/scores", "high").await.unwrap(), 100);
+    // The backup is restored, and the broken file kept for a closer look
+    let restored: GroupData = serde_json::from_slice(&std::fs::read(&save).unwrap()).unwrap();
+    assert_eq!(restored["high"], 100);
+    let quarantined = quarantined(&dir.path().join("game"));
+    assert_eq!(quarantined.len(), 1);
+    assert!(quarantined[0].starts_with("scores.save.corrupt-"));
+}
+
+#[tokio::test]
+async fn corrupt_saves_without_a_backup_start_over() {
+    let dir = TempDir::new().unwrap();
+    std::fs::create_dir_all(dir.path().join("game")).unwrap();
+    std::fs::write(dir.path().join("game/scores.save"), "not json").unwrap();
+
+    let persistence = json_store(&dir);
+    let err = persistence.load("game/scores", "high").await.unwrap_err();
+    assert_eq!(error_kind(err), ErrorKind::KeyNotFound);
+    assert!(persistence
+        .list_keys("game/scores")
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(!dir.path().join("game/scores.save").exists());
+    assert_eq!(quarantined(&dir.path().join("game")).len(), 1);
+}
+
+#[tokio::test]
+async fn interrupted_writes_keep_the_previous_save() {
+    let dir = TempDir::new().unwrap();
+    {
+        let persistence = json_store(&dir);
+        persistence.save("game/scores", "high", 100).await.unwrap();
+        persistence.flush().await.unwrap();
+    }
+    let save = dir.path().join("game/scores.save");
+
+    // Cut off while writing the new version
+    std::fs::write(dir.path().join("game/scores.save.tmp"), r#"{"hi"#).unwrap();
+    let persistence = json_store(&dir);
+    assert_eq!(persistence.load("game/scores", "high").await.unwrap(), 100);
+    drop(persistence);
+
+    // Cut off after the old version was moved to the backup, before the new one took its place
+    std::fs::rename(&save, dir.path().join("game/scores.save.bak")).unwrap();
+    let persistence = json_store(&dir);
+    assert_eq!(persistence.load("game/scores", "high").await.unwrap(), 100);
+    assert!(save.exists());
+
+    // The leftover temp file doesn't get in the way of the next write
+    persistence.save("game/scores", "high", 300).await.unwrap();
+    persistence.flush().await.unwrap();
+    drop(persistence);
+    let persistence = json_store(&dir);
+    assert_eq!(persistence.load("game/scores", "high").await.unwrap(), 300);
+    assert!(quarantined(&dir.path().join("game")).is_empty());
+}
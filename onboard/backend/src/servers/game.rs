@@ -1,13 +1,23 @@
 use crate::command::handle;
-use crate::servers::open_server;
-use anyhow::anyhow;
-use devcade_onboard_types::{Request, RequestBody, Response, ResponseBody};
+use crate::servers::{open_server, parse_request, send_response, Session};
+use devcade_onboard_types::error::{ErrorKind, ResponseError};
+use devcade_onboard_types::{Capability, Request, RequestBody, Response, ResponseBody};
 use futures_util::future;
 use std::sync::Arc;
-use tokio::io::{AsyncWriteExt, Lines, WriteHalf};
+use tokio::io::{Lines, WriteHalf};
 use tokio::sync::Mutex;
 use tokio::task;
 
+/**
+ * Capabilities offered to games connecting to the game socket
+ */
+pub const CAPABILITIES: &[Capability] = &[
+    Capability::Persistence,
+    Capability::Nfc,
+    Capability::Leaderboards,
+    Capability::Achievements,
+];
+
 pub async fn main(command_pipe: &str) -> ! {
     log::info!("Starting save/load process");
     log::debug!("Opened command pipe at {}", command_pipe);
@@ -17,43 +27,138 @@ pub async fn main(command_pipe: &str) -> ! {
         async move |mut lines: Lines<_>, writer: WriteHalf<_>| {
             let writer = Arc::new(Mutex::new(writer));
             let mut handles = vec![];
+            let mut session = Session::legacy(CAPABILITIES);
             log::debug!("New client connected to game socket");
             while let Some(line) = lines.next_line().await? {
-                let command: Request = serde_json::from_str(&line)?;
+                let command: Request = match parse_request(&line) {
+                    Ok(command) => command,
+                    Err(response) => {
+                        send_response(&writer, &response, session.protocol_version).await?;
+                        continue;
+                    }
+                };
+
+                if let RequestBody::Hello(hello) = &command.body {
+                    log::debug!("Handling command: {command}");
+                    let (body, refused) = match session.handshake(hello, CAPABILITIES) {
+                        Ok(welcome) => (ResponseBody::Welcome(welcome), false),
+                        Err(reason) => (ResponseBody::Err(reason), true),
+                    };
+                    let response = Response {
+                        request_id: command.request_id,
+                        body,
+                    };
+                    log::debug!("Sending: {response}");
+                    send_response(&writer, &response, session.protocol_version).await?;
+                    if refused {
+                        log::warn!("Refused incompatible game on game socket");
+                        break;
+                    }
+                    continue;
+                }
 
                 let writer = writer.clone();
+                let session = session.clone();
 
                 handles.push(task::spawn(async move {
                     let body: ResponseBody = match &command.body {
                         RequestBody::Ping => {
                             log::trace!("Handling command: {command}");
-                            handle(command.body).await
+                            handle(command.body, None).await
                         }
                         RequestBody::Save(_, _, _)
                         | RequestBody::Load(_, _)
+                        | RequestBody::SaveValue(_, _, _)
+                        | RequestBody::LoadValue(_, _)
+                        | RequestBody::SaveBytes(_, _, _)
+                        | RequestBody::LoadBytes(_, _)
+                        | RequestBody::Increment(_, _, _)
+                        | RequestBody::CompareAndSwap(_, _, _, _)
+                        | RequestBody::Delete(_, _)
+                        | RequestBody::ListKeys(_)
+                        | RequestBody::ListGroups
+                        | RequestBody::ClearGroup(_)
                         | RequestBody::Flush
-                        | RequestBody::GetNfcTag(_)
-                        | RequestBody::GetNfcUser(_) => {
+                        | RequestBody::SavePlayer(_, _, _, _)
+                        | RequestBody::LoadPlayer(_, _, _)
+                        | RequestBody::DeletePlayer(_, _, _)
+                        | RequestBody::ListPlayerKeys(_, _)
+                        | RequestBody::ListPlayerGroups(_)
+                        | RequestBody::ClearPlayerGroup(_, _)
+                            if session.has(&Capability::Persistence) =>
+                        {
+                            log::debug!("Handling command: {command}");
+                            handle(command.body, None).await
+                        }
+                        RequestBody::SubmitScore(_, _, _)
+                        | RequestBody::SubmitPlayerScore(_, _, _, _)
+                        | RequestBody::GetLeaderboard(_, _)
+                            if session.has(&Capability::Leaderboards) =>
+                        {
+                            log::debug!("Handling command: {command}");
+                            handle(command.body, None).await
+                        }
+                        RequestBody::DefineAchievements(_)
+                        | RequestBody::UnlockAchievement(_)
+                        | RequestBody::UnlockPlayerAchievement(_, _)
+                            if session.has(&Capability::Achievements) =>
+                        {
+                            log::debug!("Handling command: {command}");
+                            handle(command.body, None).await
+                        }
+                        RequestBody::GetNfcTag(_) | RequestBody::GetNfcUser(_)
+                            if session.has(&Capability::Nfc) =>
+                        {
                             log::debug!("Handling command: {command}");
-                            handle(command.body).await
+                            handle(command.body, None).await
                         }
+                        RequestBody::Save(_, _, _)
+                        | RequestBody::Load(_, _)
+                        | RequestBody::SaveValue(_, _, _)
+                        | RequestBody::LoadValue(_, _)
+                        | RequestBody::SaveBytes(_, _, _)
+                        | RequestBody::LoadBytes(_, _)
+                        | RequestBody::Increment(_, _, _)
+                        | RequestBody::CompareAndSwap(_, _, _, _)
+                        | RequestBody::Delete(_, _)
+                        | RequestBody::ListKeys(_)
+                        | RequestBody::ListGroups
+                        | RequestBody::ClearGroup(_)
+                        | RequestBody::Flush
+                        | RequestBody::SavePlayer(_, _, _, _)
+                        | RequestBody::LoadPlayer(_, _, _)
+                        | RequestBody::DeletePlayer(_, _, _)
+                        | RequestBody::ListPlayerKeys(_, _)
+                        | RequestBody::ListPlayerGroups(_)
+                        | RequestBody::ClearPlayerGroup(_, _)
+                        | RequestBody::SubmitScore(_, _, _)
+                        | RequestBody::SubmitPlayerScore(_, _, _, _)
+                        | RequestBody::GetLeaderboard(_, _)
+                        | RequestBody::DefineAchievements(_)
+                        | RequestBody::UnlockAchievement(_)
+                        | RequestBody::UnlockPlayerAchievement(_, _)
+                        | RequestBody::GetNfcTag(_)
+                        | RequestBody::GetNfcUser(_) => ResponseError::new(
+                            ErrorKind::Unsupported,
+                            format!("Capability for command was not negotiated: {command}"),
+                        )
+                        .into(),
                         // Don't allow game save/load to (for example) download a game, launch a game,
                         // etc. If games could launch other games, it would update the 'current game' in
                         // crate::api and allow games to corrupt other games' save data (possibly
                         // maliciously!)
-                        _ => anyhow!("Invalid command: {}", command).into(),
+                        _ => ResponseError::new(
+                            ErrorKind::InvalidRequest,
+                            format!("Invalid command: {command}"),
+                        )
+                        .into(),
                     };
                     let response = Response {
                         request_id: command.request_id,
                         body,
                     };
                     log::debug!("Sending: {response}");
-                    let mut response = serde_json::to_vec(&response)?;
-                    response.push(b'\n');
-
-                    let mut writer = writer.lock().await;
-                    writer.write_all(&response).await?;
-                    Ok(()) as Result<(), anyhow::Error>
+                    send_response(&writer, &response, session.protocol_version).await
                 }));
             }
 
@@ -1,13 +1,22 @@
 use anyhow::anyhow;
+use devcade_onboard_types::{
+    error::{ErrorKind, ResponseError},
+    Capability, Hello, Progress, Request, Response, ResponseBody, Value, Welcome,
+    LEGACY_PROTOCOL_VERSION, MIN_PROTOCOL_VERSION, PARTIAL_GAME_LIST_VERSION, PROTOCOL_VERSION,
+    STALE_RESPONSE_VERSION, STRUCTURED_ERRORS_VERSION,
+};
 use futures_util::future;
 use futures_util::FutureExt;
 use log::{log, Level};
+use serde::Serialize;
 use std::fs::remove_file;
 use std::future::Future;
 use std::process::{Command, Stdio};
 use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, BufReader, Lines, ReadHalf, WriteHalf};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines, ReadHalf, WriteHalf};
 use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{mpsc, Mutex};
 use tokio::task;
 use tokio::task::JoinError;
 
@@ -151,6 +160,280 @@ impl Default for ThreadHandles {
     }
 }
 
+/**
+ * Capabilities that are only granted to clients that ask for them in a `Hello`. Most send lines a
+ * client didn't ask for (like progress updates before a response), which clients from before the
+ * handshake can't parse. Admin requests can read or remove every game's save data, so clients have
+ * to ask for those explicitly too.
+ */
+pub const HANDSHAKE_ONLY: &[Capability] = &[
+    Capability::Events,
+    Capability::Progress,
+    Capability::Cancel,
+    Capability::Admin,
+];
+
+/**
+ * Protocol state negotiated with a single client connection
+ */
+#[derive(Debug, Clone)]
+pub struct Session {
+    /// Protocol version used for this connection
+    pub protocol_version: u32,
+    /// Capabilities granted to this connection
+    pub capabilities: Vec<Capability>,
+    /// Whether the client has already sent a `Hello`
+    handshake_done: bool,
+}
+
+impl Session {
+    /**
+     * Session for a client that hasn't (yet) sent a `Hello`. Such clients predate the handshake,
+     * so they get what the socket offers except for [`HANDSHAKE_ONLY`] capabilities, which they
+     * wouldn't know how to handle.
+     */
+    #[must_use]
+    pub fn legacy(offered: &[Capability]) -> Self {
+        Self {
+            protocol_version: LEGACY_PROTOCOL_VERSION,
+            capabilities: offered
+                .iter()
+                .filter(|capability| !HANDSHAKE_ONLY.contains(capability))
+                .cloned()
+                .collect(),
+            handshake_done: false,
+        }
+    }
+
+    /**
+     * Check if a capability was granted to this connection
+     */
+    #[must_use]
+    pub fn has(&self, capability: &Capability) -> bool {
+        self.capabilities.contains(capability)
+    }
+
+    /**
+     * Negotiate the protocol version and capabilities with a client. Clients newer than the
+     * backend are downgraded to `PROTOCOL_VERSION`, clients older than `MIN_PROTOCOL_VERSION` are
+     * refused. Only capabilities that are both requested and offered by the socket are granted.
+     *
+     * # Errors
+     * Returns the reason if the client should be refused.
+     */
+    pub fn handshake(
+        &mut self,
+        hello: &Hello,
+        offered: &[Capability],
+    ) -> Result<Welcome, ResponseError> {
+        if self.handshake_done {
+            return Err(ResponseError::new(
+                ErrorKind::InvalidRequest,
+                "Handshake was already completed on this connection",
+            ));
+        }
+        if hello.protocol_version < MIN_PROTOCOL_VERSION {
+            return Err(ResponseError::new(
+                ErrorKind::Unsupported,
+                format!(
+                "Protocol version {} is no longer supported, the backend requires at least version \
+                 {MIN_PROTOCOL_VERSION} (current is {PROTOCOL_VERSION}). Please update the client.",
+                    hello.protocol_version
+                ),
+            ));
+        }
+        if hello.protocol_version > PROTOCOL_VERSION {
+            log::info!(
+                "Client speaks protocol version {}, downgrading to {PROTOCOL_VERSION}",
+                hello.protocol_version
+            );
+        }
+        for capability in &hello.capabilities {
+            if !offered.contains(capability) {
+                log::info!("Client requested capability '{capability}', which is not offered here");
+            }
+        }
+
+        self.protocol_version = hello.protocol_version.min(PROTOCOL_VERSION);
+        self.capabilities = hello
+            .capabilities
+            .iter()
+            .filter(|capability| offered.contains(capability))
+            .cloned()
+            .collect();
+        self.handshake_done = true;
+
+        Ok(Welcome {
+            protocol_version: self.protocol_version,
+            capabilities: self.capabilities.clone(),
+        })
+    }
+}
+
+/**
+ * Parse a line received from a client into a request. If the line can't be parsed, an error
+ * response explaining why is returned instead, so that the client gets something better than a
+ * dropped connection. The request ID is recovered from the line if at all possible.
+ *
+ * # Errors
+ * Returns the response that should be sent to the client if the line isn't a valid request.
+ */
+pub fn parse_request(line: &str) -> Result<Request, Box<Response>> {
+    serde_json::from_str::<Request>(line).map_err(|err| {
+        let request_id = serde_json::from_str::<Value>(line)
+            .ok()
+            .and_then(|value| value.get("request_id")?.as_u64())
+            .and_then(|id| u32::try_from(id).ok())
+            .unwrap_or(0);
+        log::warn!("Received invalid request: {err}");
+        Box::new(Response {
+            request_id,
+            body: ResponseError::new(
+                ErrorKind::InvalidRequest,
+                format!(
+                    "Could not parse request. The backend speaks protocol version \
+                     {PROTOCOL_VERSION}, send a Hello to check compatibility."
+                ),
+            )
+            .with_details(err)
+            .into(),
+        })
+    })
+}
+
+/**
+ * Serialize a message (usually a `Response` or an `Event`) and write it to a client as a single
+ * line.
+ *
+ * # Errors
+ * This function will return an error if the message can't be serialized or written.
+ */
+pub async fn send_message<T: Serialize>(
+    writer: &Mutex<WriteHalf<UnixStream>>,
+    message: &T,
+) -> Result<(), anyhow::Error> {
+    let mut message = serde_json::to_vec(message)?;
+    message.push(b'\n');
+
+    let mut writer = writer.lock().await;
+    writer.write_all(&message).await?;
+    Ok(())
+}
+
+/**
+ * Send a response to a client speaking the given protocol version. Clients older than
+ * `STRUCTURED_ERRORS_VERSION` get errors as a plain string, like they always did. Clients older
+ * than `PARTIAL_GAME_LIST_VERSION` only get the games of a partial game list. Clients older than
+ * `STALE_RESPONSE_VERSION` get a stale response without being told it is stale. Error kinds a
+ * client's version doesn't know about are sent as `ErrorKind::Internal`.
+ *
+ * # Errors
+ * This function will return an error if the response can't be serialized or written.
+ */
+pub async fn send_response(
+    writer: &Mutex<WriteHalf<UnixStream>>,
+    response: &Response,
+    protocol_version: u32,
+) -> Result<(), anyhow::Error> {
+    let body = match &response.body {
+        ResponseBody::Stale(stale) if protocol_version < STALE_RESPONSE_VERSION => &*stale.body,
+        body => body,
+    };
+    match body {
+        ResponseBody::Err(error) if protocol_version < STRUCTURED_ERRORS_VERSION => {
+            let legacy = serde_json::json!({
+                "request_id": response.request_id,
+                "type": "Err",
+                "data": error.to_string(),
+            });
+            send_message(writer, &legacy).await
+        }
+        ResponseBody::Err(error) if error.kind.introduced_in() > protocol_version => {
+            let legacy = Response {
+                request_id: response.request_id,
+                body: ResponseBody::Err(ResponseError {
+                    kind: ErrorKind::Internal,
+                    ..error.clone()
+                }),
+            };
+            send_message(writer, &legacy).await
+        }
+        ResponseBody::PartialGameList(list) if protocol_version < PARTIAL_GAME_LIST_VERSION => {
+            let legacy = Response {
+                request_id: response.request_id,
+                body: ResponseBody::GameList(list.games.clone()),
+            };
+            send_message(writer, &legacy).await
+        }
+        body => {
+            let response = ResponseRef {
+                request_id: response.request_id,
+                body,
+            };
+            send_message(writer, &response).await
+        }
+    }
+}
+
+/**
+ * A response that borrows its body, so part of a response can be sent without copying it
+ */
+#[derive(Serialize)]
+struct ResponseRef<'a> {
+    request_id: u32,
+    #[serde(flatten)]
+    body: &'a ResponseBody,
+}
+
+/**
+ * Forward every published event to a client until the returned task is aborted or the client
+ * goes away.
+ */
+pub fn forward_events(writer: Arc<Mutex<WriteHalf<UnixStream>>>) -> task::JoinHandle<()> {
+    let mut events = crate::events::subscribe();
+    task::spawn(async move {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(missed)) => {
+                    log::warn!("Subscriber fell behind, dropped {missed} events");
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            log::debug!("Sending event: {event}");
+            if let Err(err) = send_message(&writer, &event).await {
+                log::warn!("Couldn't send event to subscriber: {err}");
+                break;
+            }
+        }
+    })
+}
+
+/**
+ * Forward progress reports for a request to a client until the sending side is dropped, which
+ * happens once the request has been handled.
+ */
+pub fn forward_progress(
+    writer: Arc<Mutex<WriteHalf<UnixStream>>>,
+    request_id: u32,
+    mut progress: mpsc::UnboundedReceiver<Progress>,
+) -> task::JoinHandle<()> {
+    task::spawn(async move {
+        while let Some(progress) = progress.recv().await {
+            let response = Response {
+                request_id,
+                body: ResponseBody::Progress(progress),
+            };
+            log::trace!("Sending: {response}");
+            if let Err(err) = send_message(&writer, &response).await {
+                log::warn!("Couldn't send progress to client: {err}");
+                break;
+            }
+        }
+    })
+}
+
 pub async fn open_server<'a, T, U>(path: &str, handle_client: T) -> !
 where
     T: (Fn(Lines<BufReader<ReadHalf<UnixStream>>>, WriteHalf<UnixStream>) -> U)
@@ -1,13 +1,63 @@
 use crate::command::handle;
-use crate::servers::open_server;
-use devcade_onboard_types::{Request, RequestBody, Response, ResponseBody};
-use futures_util::future;
+use crate::servers::{
+    forward_events, forward_progress, open_server, parse_request, send_response, Session,
+};
+use devcade_onboard_types::error::{ErrorKind, ResponseError};
+use devcade_onboard_types::{Capability, Request, RequestBody, Response, ResponseBody};
+use futures_util::future::{self, AbortHandle, Abortable};
 use log::{log, Level};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::io::{AsyncWriteExt, Lines, WriteHalf};
-use tokio::sync::Mutex;
+use tokio::io::{Lines, WriteHalf};
+use tokio::sync::{mpsc, Mutex};
 use tokio::task;
 
+/**
+ * Capabilities offered to clients of the onboard socket
+ */
+pub const CAPABILITIES: &[Capability] = &[
+    Capability::Persistence,
+    Capability::Nfc,
+    Capability::Events,
+    Capability::Progress,
+    Capability::Cancel,
+    Capability::GameControl,
+    Capability::Stats,
+    Capability::Admin,
+];
+
+/**
+ * Capability a client has to have negotiated before it may send a request, if any
+ */
+fn required_capability(body: &RequestBody) -> Option<Capability> {
+    match body {
+        RequestBody::KillGame | RequestBody::GetRunningGame => Some(Capability::GameControl),
+        RequestBody::GetPlayStats(_) | RequestBody::GetPopularGames(_, _) => {
+            Some(Capability::Stats)
+        }
+        RequestBody::ExportSaves(_)
+        | RequestBody::ImportSaves(_, _)
+        | RequestBody::GetStorageUsage => Some(Capability::Admin),
+        _ => None,
+    }
+}
+
+/**
+ * Whether only the running game may send a request, because it acts on the game's behalf. Letting
+ * the frontend send these would let it write into whatever game happens to be running.
+ */
+fn game_only(body: &RequestBody) -> bool {
+    matches!(
+        body,
+        RequestBody::SubmitScore(_, _, _)
+            | RequestBody::SubmitPlayerScore(_, _, _, _)
+            | RequestBody::DefineAchievements(_)
+            | RequestBody::UnlockAchievement(_)
+            | RequestBody::UnlockPlayerAchievement(_, _)
+    )
+}
+
 /**
  * Main function for the onboard process. This function handles all communication to/from the onboard
  * process. It reads commands from the command pipe and writes responses to the response pipe.
@@ -29,8 +79,18 @@ pub async fn main(command_pipe: &str) -> ! {
         async move |mut lines: Lines<_>, writer: WriteHalf<_>| {
             let writer = Arc::new(Mutex::new(writer));
             let mut handles = vec![];
+            let mut session = Session::legacy(CAPABILITIES);
+            let mut subscription: Option<task::JoinHandle<()>> = None;
+            // Requests that are still being handled, by request ID, so they can be cancelled
+            let in_flight: Arc<std::sync::Mutex<HashMap<u32, AbortHandle>>> = Arc::default();
             while let Some(line) = lines.next_line().await? {
-                let command: Request = serde_json::from_str(&line)?;
+                let command: Request = match parse_request(&line) {
+                    Ok(command) => command,
+                    Err(response) => {
+                        send_response(&writer, &response, session.protocol_version).await?;
+                        continue;
+                    }
+                };
 
                 if let RequestBody::Ping = &command.body {
                     log!(Level::Trace, "Handling command: {}", command);
@@ -38,10 +98,165 @@ pub async fn main(command_pipe: &str) -> ! {
                     log!(Level::Debug, "Handling command: {}", command);
                 }
 
+                if let RequestBody::Hello(hello) = &command.body {
+                    let (body, refused) = match session.handshake(hello, CAPABILITIES) {
+                        Ok(welcome) => (ResponseBody::Welcome(welcome), false),
+                        Err(reason) => (ResponseBody::Err(reason), true),
+                    };
+                    let response = Response {
+                        request_id: command.request_id,
+                        body,
+                    };
+                    log::debug!("Sending: {response}");
+                    send_response(&writer, &response, session.protocol_version).await?;
+                    if refused {
+                        log::warn!("Refused incompatible client on onboard socket");
+                        break;
+                    }
+                    continue;
+                }
+
+                if let RequestBody::Subscribe | RequestBody::Unsubscribe = &command.body {
+                    let body = if !session.has(&Capability::Events) {
+                        ResponseError::new(
+                            ErrorKind::Unsupported,
+                            "Events capability was not negotiated",
+                        )
+                        .into()
+                    } else {
+                        if let Some(subscription) = subscription.take() {
+                            subscription.abort();
+                        }
+                        if let RequestBody::Subscribe = &command.body {
+                            subscription = Some(forward_events(writer.clone()));
+                        }
+                        ResponseBody::Ok
+                    };
+                    let response = Response {
+                        request_id: command.request_id,
+                        body,
+                    };
+                    log::debug!("Sending: {response}");
+                    send_response(&writer, &response, session.protocol_version).await?;
+                    continue;
+                }
+
+                if let RequestBody::Cancel(request_id) = &command.body {
+                    let body = if !session.has(&Capability::Cancel) {
+                        ResponseError::new(
+                            ErrorKind::Unsupported,
+                            "Cancel capability was not negotiated",
+                        )
+                        .into()
+                    } else if let Some(handle) = in_flight.lock().unwrap().remove(request_id) {
+                        // The cancelled request answers for itself with an error
+                        handle.abort();
+                        ResponseBody::Ok
+                    } else {
+                        ResponseError::new(
+                            ErrorKind::InvalidRequest,
+                            format!("No request with id {request_id} is in flight"),
+                        )
+                        .into()
+                    };
+                    let response = Response {
+                        request_id: command.request_id,
+                        body,
+                    };
+                    log::debug!("Sending: {response}");
+                    send_response(&writer, &response, session.protocol_version).await?;
+                    continue;
+                }
+
+                if game_only(&command.body) {
+                    let response = Response {
+                        request_id: command.request_id,
+                        body: ResponseError::new(
+                            ErrorKind::InvalidRequest,
+                            format!("Invalid command: {command}"),
+                        )
+                        .into(),
+                    };
+                    log::debug!("Sending: {response}");
+                    send_response(&writer, &response, session.protocol_version).await?;
+                    continue;
+                }
+
+                if let Some(capability) = required_capability(&command.body) {
+                    if !session.has(&capability) {
+                        let response = Response {
+                            request_id: command.request_id,
+                            body: ResponseError::new(
+                                ErrorKind::Unsupported,
+                                format!("{capability} capability was not negotiated"),
+                            )
+                            .into(),
+                        };
+                        log::debug!("Sending: {response}");
+                        send_response(&writer, &response, session.protocol_version).await?;
+                        continue;
+                    }
+                }
+
+                let (abort_handle, abort_registration) = AbortHandle::new_pair();
+                let duplicate = match in_flight.lock().unwrap().entry(command.request_id) {
+                    Entry::Occupied(_) => true,
+                    Entry::Vacant(entry) => {
+                        entry.insert(abort_handle);
+                        false
+                    }
+                };
+                if duplicate {
+                    // Replacing the earlier request would make it impossible to cancel
+                    let response = Response {
+                        request_id: command.request_id,
+                        body: ResponseError::new(
+                            ErrorKind::InvalidRequest,
+                            format!(
+                                "A request with id {} is already in flight",
+                                command.request_id
+                            ),
+                        )
+                        .into(),
+                    };
+                    log::debug!("Sending: {response}");
+                    send_response(&writer, &response, session.protocol_version).await?;
+                    continue;
+                }
+
                 let writer = writer.clone();
+                let wants_progress = session.has(&Capability::Progress);
+                let protocol_version = session.protocol_version;
+                let in_flight = in_flight.clone();
 
                 handles.push(task::spawn(async move {
-                    let body = handle(command.body).await;
+                    let (progress, forwarder) = if wants_progress {
+                        let (tx, rx) = mpsc::unbounded_channel();
+                        let forwarder = forward_progress(writer.clone(), command.request_id, rx);
+                        (Some(tx), Some(forwarder))
+                    } else {
+                        (None, None)
+                    };
+                    // Only the handler is aborted on cancel, never a half written response
+                    let body =
+                        match Abortable::new(handle(command.body, progress), abort_registration)
+                            .await
+                        {
+                            Ok(body) => body,
+                            Err(_) => {
+                                log::info!("Request {} was cancelled", command.request_id);
+                                ResponseError::new(
+                                    ErrorKind::Cancelled,
+                                    format!("Request {} was cancelled", command.request_id),
+                                )
+                                .into()
+                            }
+                        };
+                    in_flight.lock().unwrap().remove(&command.request_id);
+                    // Make sure all progress has been sent before the final response
+                    if let Some(forwarder) = forwarder {
+                        let _ = forwarder.await;
+                    }
                     let response = Response {
                         request_id: command.request_id,
                         body,
@@ -50,14 +265,12 @@ pub async fn main(command_pipe: &str) -> ! {
                         ResponseBody::Pong => log::trace!("Sending: {response}"),
                         _ => log::debug!("Sending: {response}"),
                     }
-                    let mut response = serde_json::to_vec(&response)?;
-                    response.push(b'\n');
-
-                    let mut writer = writer.lock().await;
-                    writer.write_all(&response).await?;
-                    Ok(()) as Result<(), anyhow::Error>
+                    send_response(&writer, &response, protocol_version).await
                 }));
             }
+            if let Some(subscription) = subscription {
+                subscription.abort();
+            }
             future::join_all(handles).await;
             Ok(())
         },
@@ -0,0 +1,249 @@
+use crate::persistence::save_root;
+use anyhow::Error;
+use devcade_onboard_types::{PlaySession, PlayStats};
+use lazy_static::lazy_static;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+lazy_static! {
+    // Serializes appends so concurrent sessions can't interleave lines
+    static ref LOG_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/**
+ * Path of the play session log. Every line is one JSON encoded `PlaySession`.
+ */
+fn log_path() -> PathBuf {
+    save_root().join("sessions.jsonl")
+}
+
+/**
+ * Seconds since the unix epoch
+ */
+#[must_use]
+pub fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/**
+ * Append a finished session to the play session log.
+ *
+ * # Errors
+ * This function will return an error if the log can't be written.
+ */
+pub async fn record(session: &PlaySession) -> Result<(), Error> {
+    let mut line = serde_json::to_vec(session)?;
+    line.push(b'\n');
+
+    let _lock = LOG_LOCK.lock().await;
+    let path = log_path();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .await?;
+    file.write_all(&line).await?;
+    file.sync_data().await?;
+    Ok(())
+}
+
+/**
+ * Read every session from the play session log. Lines that can't be parsed (e.g. because power
+ * was cut while writing them) are skipped.
+ *
+ * # Errors
+ * This function will return an error if the log exists but can't be read.
+ */
+async fn sessions() -> Result<Vec<PlaySession>, Error> {
+    let _lock = LOG_LOCK.lock().await;
+    let path = log_path();
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    Ok(fs::read_to_string(&path)
+        .await?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(session) => Some(session),
+            Err(err) => {
+                log::warn!("Skipping bad line in play session log: {err}");
+                None
+            }
+        })
+        .collect())
+}
+
+/**
+ * Sum up sessions into per-game statistics, counting only the play time between `since` and
+ * `until` (in seconds since the unix epoch). Sessions entirely outside of that window are
+ * skipped.
+ */
+fn aggregate<'a>(
+    sessions: impl Iterator<Item = &'a PlaySession>,
+    since: u64,
+    until: u64,
+) -> HashMap<String, PlayStats> {
+    let mut stats: HashMap<String, PlayStats> = HashMap::new();
+    let mut players: HashMap<String, HashSet<&str>> = HashMap::new();
+    for session in
+        sessions.filter(|session| session.ended_at >= since && session.started_at <= until)
+    {
+        let game = stats
+            .entry(session.game_id.clone())
+            .or_insert_with(|| PlayStats {
+                game_id: session.game_id.clone(),
+                ..Default::default()
+            });
+        game.sessions += 1;
+        game.total_secs += session
+            .ended_at
+            .min(until)
+            .saturating_sub(session.started_at.max(since));
+        game.last_played = game.last_played.max(Some(session.ended_at));
+        if let Some(player) = &session.player {
+            players
+                .entry(session.game_id.clone())
+                .or_default()
+                .insert(player);
+        }
+    }
+    for (game_id, players) in players {
+        if let Some(game) = stats.get_mut(&game_id) {
+            game.players = players.len() as u64;
+        }
+    }
+    stats
+}
+
+/**
+ * Get all-time play statistics for a game. Games that were never played have empty statistics.
+ *
+ * # Errors
+ * This function will return an error if the play session log can't be read.
+ */
+pub async fn play_stats(game_id: String) -> Result<PlayStats, Error> {
+    let sessions = sessions().await?;
+    Ok(aggregate(
+        sessions.iter().filter(|session| session.game_id == game_id),
+        0,
+        u64::MAX,
+    )
+    .remove(&game_id)
+    .unwrap_or(PlayStats {
+        game_id,
+        ..Default::default()
+    }))
+}
+
+/**
+ * Rank games by their play time between `since` and `until`, most played first
+ */
+fn most_played(sessions: &[PlaySession], since: u64, until: u64, limit: u32) -> Vec<PlayStats> {
+    let mut games: Vec<PlayStats> = aggregate(sessions.iter(), since, until)
+        .into_values()
+        .collect();
+    games.sort_by(|a, b| {
+        b.total_secs
+            .cmp(&a.total_secs)
+            .then(b.sessions.cmp(&a.sessions))
+            .then(a.game_id.cmp(&b.game_id))
+    });
+    games.truncate(limit as usize);
+    games
+}
+
+/**
+ * Get the games with the most play time in the last `window_secs` seconds, most played first.
+ * Only the part of a session inside the window counts.
+ *
+ * # Errors
+ * This function will return an error if the play session log can't be read.
+ */
+pub async fn popular_games(window_secs: u64, limit: u32) -> Result<Vec<PlayStats>, Error> {
+    let now = unix_secs(SystemTime::now());
+    let sessions = sessions().await?;
+    Ok(most_played(
+        &sessions,
+        now.saturating_sub(window_secs),
+        now,
+        limit,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(game_id: &str, started_at: u64, ended_at: u64, player: Option<&str>) -> PlaySession {
+        PlaySession {
+            game_id: game_id.to_string(),
+            started_at,
+            ended_at,
+            exit_code: Some(0),
+            player: player.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn sessions_are_summed_per_game() {
+        let sessions = [
+            session("a", 100, 200, Some("alice")),
+            session("a", 300, 350, Some("alice")),
+            session("a", 400, 410, Some("bob")),
+            session("b", 500, 600, None),
+        ];
+
+        let stats = aggregate(sessions.iter(), 0, u64::MAX);
+        let a = &stats["a"];
+        assert_eq!(a.sessions, 3);
+        assert_eq!(a.total_secs, 160);
+        assert_eq!(a.players, 2);
+        assert_eq!(a.last_played, Some(410));
+        let b = &stats["b"];
+        assert_eq!(b.sessions, 1);
+        assert_eq!(b.players, 0);
+    }
+
+    #[test]
+    fn only_play_time_inside_the_window_counts() {
+        let sessions = [
+            // Three hours, ending a minute into the window
+            session("long", 0, 10_860, None),
+            session("short", 10_900, 11_200, None),
+            session("before", 0, 100, None),
+        ];
+
+        let stats = aggregate(sessions.iter(), 10_800, 11_400);
+        assert_eq!(stats["long"].total_secs, 60);
+        assert_eq!(stats["short"].total_secs, 300);
+        assert!(!stats.contains_key("before"));
+    }
+
+    #[test]
+    fn popular_games_are_ranked_by_play_time() {
+        let sessions = [
+            session("a", 0, 100, None),
+            session("b", 0, 300, None),
+            session("c", 0, 50, None),
+            session("c", 50, 100, None),
+            session("d", 0, 200, None),
+        ];
+
+        let ranked: Vec<String> = most_played(&sessions, 0, 1000, 3)
+            .into_iter()
+            .map(|game| game.game_id)
+            .collect();
+        // c ties with a on play time but has more sessions
+        assert_eq!(ranked, ["b", "d", "c"]);
+    }
+}
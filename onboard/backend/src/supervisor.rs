@@ -0,0 +1,258 @@
+use crate::events;
+use crate::stats::{self, unix_secs};
+use anyhow::Error;
+use devcade_onboard_types::error::{ErrorKind, ResponseError};
+use devcade_onboard_types::{Event, PlaySession, RunningGame};
+use lazy_static::lazy_static;
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use std::collections::HashSet;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::process::{Child, Command};
+use tokio::sync::{oneshot, Notify};
+
+// How long a game gets to exit after SIGTERM before it is killed
+const GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    static ref SESSION: Mutex<Option<GameSession>> = Mutex::new(None);
+    // Notified whenever a session ends
+    static ref SESSION_ENDED: Notify = Notify::new();
+}
+
+/**
+ * The game process currently owned by the supervisor
+ */
+struct GameSession {
+    game_id: String,
+    pid: Option<u32>,
+    started_at: SystemTime,
+    started: Instant,
+    // Per-game NFC handle of whoever identified themselves while the game was running
+    player: Option<String>,
+    // Everyone who identified themselves, who the game may keep player save data for
+    players: HashSet<String>,
+    // Taken when the game is asked to stop
+    kill: Option<oneshot::Sender<()>>,
+}
+
+impl GameSession {
+    fn info(&self) -> RunningGame {
+        RunningGame {
+            game_id: self.game_id.clone(),
+            pid: self.pid,
+            started_at: unix_secs(self.started_at),
+            runtime_secs: self.started.elapsed().as_secs(),
+        }
+    }
+}
+
+/**
+ * Clears the session when the supervising future ends, even if it is dropped (e.g. because the
+ * runtime is shutting down, which kills the game through `kill_on_drop`).
+ */
+struct SessionGuard;
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        SESSION.lock().unwrap().take();
+        SESSION_ENDED.notify_waiters();
+    }
+}
+
+/**
+ * How a supervised game ended
+ */
+#[derive(Debug, Clone)]
+pub struct GameExit {
+    pub status: ExitStatus,
+    pub runtime: Duration,
+    /// Whether the game was stopped by the backend
+    pub killed: bool,
+}
+
+/**
+ * Spawn a game and supervise it until it exits. Only one game can run at a time. When the game
+ * ends, a `GameExited` event is published with its exit status and runtime.
+ *
+ * # Errors
+ * This function will return an error if another game is running or the game couldn't be spawned.
+ */
+pub async fn run(
+    game_id: String,
+    flatpak_app_id: &str,
+    command: &mut Command,
+) -> Result<GameExit, Error> {
+    let (kill_tx, kill_rx) = oneshot::channel();
+    let mut child = {
+        let mut session = SESSION.lock().unwrap();
+        if let Some(running) = session.as_ref() {
+            return Err(ResponseError::new(
+                ErrorKind::LaunchFailed,
+                format!("Game {} is already running", running.game_id),
+            )
+            .into());
+        }
+        let child = command.kill_on_drop(true).spawn().map_err(|err| {
+            ResponseError::new(ErrorKind::LaunchFailed, "Failed to launch game").with_details(err)
+        })?;
+        *session = Some(GameSession {
+            game_id: game_id.clone(),
+            pid: child.id(),
+            started_at: SystemTime::now(),
+            started: Instant::now(),
+            player: None,
+            players: HashSet::new(),
+            kill: Some(kill_tx),
+        });
+        child
+    };
+    let _guard = SessionGuard;
+    let started_at = SystemTime::now();
+    let started = Instant::now();
+    log::info!("Game {game_id} started with pid {:?}", child.id());
+
+    let (status, killed) = tokio::select! {
+        status = child.wait() => (status, false),
+        Ok(()) = kill_rx => (terminate(&mut child, flatpak_app_id).await, true),
+    };
+    let status = status.map_err(|err| {
+        ResponseError::new(ErrorKind::LaunchFailed, "Failed to wait for game").with_details(err)
+    })?;
+    let runtime = started.elapsed();
+    log::info!("Game {game_id} ended after {runtime:?} with {status}");
+
+    let player = SESSION
+        .lock()
+        .unwrap()
+        .as_mut()
+        .and_then(|session| session.player.take());
+    let session = PlaySession {
+        game_id: game_id.clone(),
+        started_at: unix_secs(started_at),
+        ended_at: unix_secs(SystemTime::now()),
+        exit_code: status.code(),
+        player,
+    };
+    if let Err(err) = stats::record(&session).await {
+        log::warn!("Couldn't record play session: {err}");
+    }
+
+    events::publish(Event::GameExited {
+        game_id,
+        exit_code: status.code(),
+        signal: status.signal(),
+        runtime_secs: runtime.as_secs(),
+        killed,
+    });
+
+    Ok(GameExit {
+        status,
+        runtime,
+        killed,
+    })
+}
+
+/**
+ * Ask the running game to stop. The game gets `SIGTERM` and a grace period to exit on its own
+ * before it and everything else in its sandbox is killed.
+ *
+ * # Errors
+ * This function will return an error if no game is running.
+ */
+pub fn kill() -> Result<(), Error> {
+    let mut session = SESSION.lock().unwrap();
+    let session = session.as_mut().ok_or_else(|| {
+        Error::from(ResponseError::new(
+            ErrorKind::NotFound,
+            "No game is running",
+        ))
+    })?;
+    match session.kill.take() {
+        Some(kill) => {
+            log::info!("Stopping game {}", session.game_id);
+            // The supervisor only goes away together with the session
+            let _ = kill.send(());
+        }
+        None => log::debug!("Game {} is already being stopped", session.game_id),
+    }
+    Ok(())
+}
+
+/**
+ * Stop the running game, if there is one, and wait until it has exited and its play session has
+ * been recorded. Used when the backend shuts down, so the game isn't left behind.
+ */
+pub async fn stop() {
+    if kill().is_err() {
+        return;
+    }
+    loop {
+        // Created before checking, so the session can't end in between unnoticed
+        let ended = SESSION_ENDED.notified();
+        if SESSION.lock().unwrap().is_none() {
+            return;
+        }
+        ended.await;
+    }
+}
+
+/**
+ * Attribute the running game's session to a player. Only the first player to identify themselves
+ * is kept.
+ */
+pub fn identify_player(handle: &str) {
+    if let Some(session) = SESSION.lock().unwrap().as_mut() {
+        session.player.get_or_insert_with(|| handle.to_owned());
+        session.players.insert(handle.to_owned());
+    }
+}
+
+/**
+ * Whether the player with this per-game NFC handle has identified themselves while the current
+ * game was running.
+ */
+#[must_use]
+pub fn player_present(handle: &str) -> bool {
+    matches!(
+        SESSION.lock().unwrap().as_ref(),
+        Some(session) if session.players.contains(handle)
+    )
+}
+
+/**
+ * Get the game that is currently running, if any
+ */
+#[must_use]
+pub fn running_game() -> Option<RunningGame> {
+    SESSION.lock().unwrap().as_ref().map(GameSession::info)
+}
+
+async fn terminate(child: &mut Child, flatpak_app_id: &str) -> std::io::Result<ExitStatus> {
+    if let Some(pid) = child.id() {
+        if let Err(err) = signal::kill(Pid::from_raw(pid as i32), Signal::SIGTERM) {
+            log::warn!("Couldn't send SIGTERM to game: {err}");
+        }
+    }
+    if let Ok(status) = tokio::time::timeout(GRACE_PERIOD, child.wait()).await {
+        return status;
+    }
+
+    log::warn!("Game didn't exit within {GRACE_PERIOD:?}, killing it");
+    child.kill().await?;
+    // `flatpak run` going away doesn't necessarily take the sandbox with it
+    match Command::new("flatpak")
+        .arg("kill")
+        .arg(flatpak_app_id)
+        .status()
+        .await
+    {
+        Ok(status) if !status.success() => log::debug!("flatpak kill exited with {status}"),
+        Ok(_) => {}
+        Err(err) => log::warn!("Couldn't run flatpak kill: {err}"),
+    }
+    child.wait().await
+}
@@ -0,0 +1,129 @@
+use crate::{events, supervisor};
+use devcade_onboard_types::Event;
+use nix::fcntl::OFlag;
+use std::fs::{File, OpenOptions};
+use std::io::Read;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+use tokio::task::{JoinHandle, JoinSet};
+
+const INPUT_DIR: &str = "/dev/input";
+
+/**
+ * Stops the running game if no input device produced an event for longer than the timeout. The
+ * frontend is told with a `GameIdle` event before the game is stopped, and the launch request
+ * finishes as soon as the game has exited, which returns the cabinet to the menu.
+ *
+ * The watchdog stops watching when it is dropped.
+ */
+pub struct Watchdog {
+    task: JoinHandle<()>,
+}
+
+impl Watchdog {
+    /**
+     * Start watching the input devices for the given game
+     */
+    #[must_use]
+    pub fn start(game_id: String, timeout: Duration) -> Self {
+        Self {
+            task: tokio::spawn(watch(game_id, timeout)),
+        }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        // Dropping the task's set of readers aborts them too
+        self.task.abort();
+    }
+}
+
+async fn watch(game_id: String, timeout: Duration) {
+    let last_input = Arc::new(Mutex::new(Instant::now()));
+
+    let mut readers = JoinSet::new();
+    for device in input_devices().await {
+        let device = match open_device(&device) {
+            Ok(device) => device,
+            Err(err) => {
+                log::debug!("Can't watch input device {device:?}: {err}");
+                continue;
+            }
+        };
+        let last_input = last_input.clone();
+        readers.spawn(async move {
+            // Every read is at least one input event, what's in it doesn't matter
+            let mut buf = [0u8; 256];
+            while let Ok(mut ready) = device.readable().await {
+                match ready.try_io(|device| device.get_ref().read(&mut buf)) {
+                    Ok(Ok(0) | Err(_)) => break,
+                    Ok(Ok(_)) => *last_input.lock().unwrap() = Instant::now(),
+                    // Readiness was stale, wait for the next event
+                    Err(_would_block) => continue,
+                }
+            }
+        });
+    }
+    if readers.is_empty() {
+        log::warn!("No readable input devices found, idle timeout is disabled");
+        return;
+    }
+
+    log::debug!("Watching for idle input on game {game_id} ({timeout:?})");
+    let interval = (timeout / 4).min(Duration::from_secs(30));
+    loop {
+        tokio::time::sleep(interval).await;
+        let idle = last_input.lock().unwrap().elapsed();
+        if idle < timeout {
+            continue;
+        }
+
+        log::info!("Game {game_id} got no input for {idle:?}, stopping it");
+        events::publish(Event::GameIdle {
+            game_id: game_id.clone(),
+            idle_secs: idle.as_secs(),
+        });
+        if let Err(err) = supervisor::kill() {
+            log::warn!("Couldn't stop idle game: {err}");
+        }
+        return;
+    }
+}
+
+/**
+ * Open an input device for non-blocking reads. A blocking read can't be interrupted, so it would
+ * keep a thread busy until the next key press even after the watchdog is stopped.
+ */
+fn open_device(path: &Path) -> std::io::Result<AsyncFd<File>> {
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(OFlag::O_NONBLOCK.bits())
+        .open(path)?;
+    AsyncFd::with_interest(file, Interest::READABLE)
+}
+
+/**
+ * Get the paths of all evdev input devices
+ */
+async fn input_devices() -> Vec<PathBuf> {
+    let mut devices = vec![];
+    let mut entries = match tokio::fs::read_dir(INPUT_DIR).await {
+        Ok(entries) => entries,
+        Err(err) => {
+            log::warn!("Couldn't list input devices in {INPUT_DIR}: {err}");
+            return devices;
+        }
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if entry.file_name().to_string_lossy().starts_with("event") {
+            devices.push(entry.path());
+        }
+    }
+    devices
+}
@@ -0,0 +1,147 @@
+use crate::{QUOTA_EXCEEDED_VERSION, STRUCTURED_ERRORS_VERSION};
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display};
+
+/**
+ * What went wrong while handling a request. Clients should match on this instead of on the error
+ * message, which is only meant for humans.
+ */
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum ErrorKind {
+    /// The request couldn't be parsed, or isn't allowed on this socket
+    InvalidRequest,
+    /// The client's protocol version or a capability it needs isn't supported
+    Unsupported,
+    /// The request was cancelled by the client
+    Cancelled,
+    /// The Devcade API couldn't be reached
+    ApiOffline,
+    /// The Devcade API answered, but with an error or something we couldn't understand
+    ApiError,
+    /// The requested game, tag or user doesn't exist
+    NotFound,
+    /// The game asks for flatpak permissions that aren't allowed on the cabinet
+    PermissionRejected,
+    /// Flatpak failed to install the game
+    InstallFailed,
+    /// The game couldn't be launched
+    LaunchFailed,
+    /// The requested key doesn't exist in the save group
+    KeyNotFound,
+    /// Save data couldn't be read or written
+    Persistence,
+    /// The save would put the game over one of its storage limits
+    QuotaExceeded,
+    /// The NFC reader or gatekeeper couldn't be reached
+    Nfc,
+    /// Reading or writing the filesystem failed
+    Io,
+    /// Anything that doesn't fit one of the other kinds
+    #[default]
+    Internal,
+    /// A kind added in a newer protocol version than this client knows about
+    #[serde(other)]
+    Unknown,
+}
+
+impl ErrorKind {
+    /**
+     * First protocol version in which clients know about this kind. Clients speaking an older
+     * version get [`ErrorKind::Internal`] instead.
+     */
+    #[must_use]
+    pub fn introduced_in(self) -> u32 {
+        match self {
+            Self::QuotaExceeded | Self::Unknown => QUOTA_EXCEEDED_VERSION,
+            _ => STRUCTURED_ERRORS_VERSION,
+        }
+    }
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+/**
+ * An error sent to a client in `ResponseBody::Err`.
+ *
+ * Clients speaking a protocol version older than 2 receive only the message as a plain string.
+ * For the same reason, a plain string is accepted when deserializing and becomes an
+ * [`ErrorKind::Internal`] error.
+ */
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(from = "ErrorRepr")]
+pub struct ResponseError {
+    /// What went wrong
+    pub kind: ErrorKind,
+    /// Human readable description of the error
+    pub message: String,
+    /// Additional information about the error, e.g. the underlying error message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<String>,
+}
+
+impl ResponseError {
+    /**
+     * Create an error of the given kind without details
+     */
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    /**
+     * Attach additional information to this error
+     */
+    #[must_use]
+    pub fn with_details(mut self, details: impl ToString) -> Self {
+        self.details = Some(details.to_string());
+        self
+    }
+}
+
+impl Display for ResponseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.details {
+            Some(details) => write!(f, "{} ({details})", self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for ResponseError {}
+
+// Accepts both the structured form and the plain string sent before protocol version 2
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ErrorRepr {
+    Structured {
+        kind: ErrorKind,
+        message: String,
+        #[serde(default)]
+        details: Option<String>,
+    },
+    Message(String),
+}
+
+impl From<ErrorRepr> for ResponseError {
+    fn from(repr: ErrorRepr) -> Self {
+        match repr {
+            ErrorRepr::Structured {
+                kind,
+                message,
+                details,
+            } => Self {
+                kind,
+                message,
+                details,
+            },
+            ErrorRepr::Message(message) => Self::new(ErrorKind::Internal, message),
+        }
+    }
+}
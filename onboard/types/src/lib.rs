@@ -1,11 +1,13 @@
+pub mod error;
 pub mod schema;
+use crate::error::{ErrorKind, ResponseError};
 use crate::schema::*;
 use anyhow::Error;
-use serde::{Deserialize, Serialize};
+use base64::Engine;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 pub use serde_json::{Map, Value};
+use std::collections::BTreeMap;
 use std::fmt::{self, Display};
-use std::process::ExitStatus;
-use std::thread::JoinHandle;
 
 /// Identifies which user is using the machine
 #[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
@@ -36,6 +38,139 @@ impl From<Player> for u8 {
     }
 }
 
+/// Version of the socket protocol spoken by this crate. This is bumped whenever a change to
+/// [`Request`] or [`Response`] would break a client built against an older version.
+///
+/// - 1: Initial version with the [`Hello`] handshake
+/// - 2: [`ResponseBody::Err`] carries a [`ResponseError`] instead of a plain string
+/// - 3: Game lists that could only be partly fetched are sent as a
+///   [`ResponseBody::PartialGameList`]
+/// - 4: Responses built from cached API data are sent as a [`ResponseBody::Stale`]
+/// - 5: Errors can be an [`ErrorKind::QuotaExceeded`], and kinds a client doesn't know are
+///   read as [`ErrorKind::Unknown`]
+pub const PROTOCOL_VERSION: u32 = 5;
+
+/// First protocol version in which errors are sent as a [`ResponseError`]. Older clients get the
+/// error message as a plain string.
+pub const STRUCTURED_ERRORS_VERSION: u32 = 2;
+
+/// First protocol version in which a [`ResponseBody::PartialGameList`] is sent. Older clients get
+/// the games that could be fetched as a [`ResponseBody::GameList`].
+pub const PARTIAL_GAME_LIST_VERSION: u32 = 3;
+
+/// First protocol version in which a [`ResponseBody::Stale`] is sent. Older clients get the
+/// response inside it, without knowing it may be out of date.
+pub const STALE_RESPONSE_VERSION: u32 = 4;
+
+/// First protocol version in which errors can be an [`ErrorKind::QuotaExceeded`]. Older
+/// clients get an [`ErrorKind::Internal`] error instead.
+pub const QUOTA_EXCEEDED_VERSION: u32 = 5;
+
+/// Oldest protocol version the backend is still willing to talk to. Clients announcing an older
+/// version in their [`Hello`] are refused.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Protocol version assumed for clients that start sending requests without a [`Hello`]. These
+/// are served exactly as they were before the handshake existed.
+pub const LEGACY_PROTOCOL_VERSION: u32 = 0;
+
+/// An optional part of the protocol that both sides have to agree on before it is used.
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum Capability {
+    /// Save / Load / Flush requests
+    Persistence,
+    /// Gatekeeper NFC requests
+    Nfc,
+    /// Subscribing to unsolicited [`Event`]s
+    Events,
+    /// Receiving [`ResponseBody::Progress`] updates before the final response to a request
+    Progress,
+    /// Cancelling in-flight requests with [`RequestBody::Cancel`]
+    Cancel,
+    /// Inspecting and killing the running game
+    GameControl,
+    /// Play time statistics
+    Stats,
+    /// Submitting scores to and reading leaderboards
+    Leaderboards,
+    /// Defining and unlocking achievements
+    Achievements,
+    /// Seeing how much save data every game uses, and exporting and importing it. Only granted to
+    /// clients that ask for it in a [`RequestBody::Hello`].
+    Admin,
+    /// A capability this side doesn't know about. These are never granted.
+    Unknown(String),
+}
+
+impl From<String> for Capability {
+    fn from(name: String) -> Self {
+        match name.as_str() {
+            "persistence" => Self::Persistence,
+            "nfc" => Self::Nfc,
+            "events" => Self::Events,
+            "progress" => Self::Progress,
+            "cancel" => Self::Cancel,
+            "game_control" => Self::GameControl,
+            "stats" => Self::Stats,
+            "leaderboards" => Self::Leaderboards,
+            "achievements" => Self::Achievements,
+            "admin" => Self::Admin,
+            _ => Self::Unknown(name),
+        }
+    }
+}
+
+impl From<Capability> for String {
+    fn from(capability: Capability) -> Self {
+        capability.to_string()
+    }
+}
+
+impl Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Persistence => write!(f, "persistence"),
+            Self::Nfc => write!(f, "nfc"),
+            Self::Events => write!(f, "events"),
+            Self::Progress => write!(f, "progress"),
+            Self::Cancel => write!(f, "cancel"),
+            Self::GameControl => write!(f, "game_control"),
+            Self::Stats => write!(f, "stats"),
+            Self::Leaderboards => write!(f, "leaderboards"),
+            Self::Achievements => write!(f, "achievements"),
+            Self::Admin => write!(f, "admin"),
+            Self::Unknown(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+/**
+ * First message sent by a client after connecting to a backend socket. Announces which protocol
+ * version the client speaks and which capabilities it would like to use.
+ */
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Hello {
+    /// Protocol version the client was built against, usually [`PROTOCOL_VERSION`]
+    pub protocol_version: u32,
+    /// Capabilities the client would like to use
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+}
+
+/**
+ * Reply of the backend to a [`Hello`]. Contains the protocol version that will be used for the
+ * rest of the connection, which may be older than the one the client asked for, and the subset of
+ * the requested capabilities the backend agreed to.
+ */
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Welcome {
+    /// Protocol version that will be used for this connection
+    pub protocol_version: u32,
+    /// Capabilities granted to this connection
+    pub capabilities: Vec<Capability>,
+}
+
 /**
  * A request received by the backend from the frontend.
  */
@@ -56,7 +191,9 @@ pub struct Request {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", content = "data")]
 pub enum RequestBody {
-    Ping, // Used to check if the backend is alive
+    Ping,         // Used to check if the backend is alive
+    Hello(Hello), // Protocol handshake, should be the first request on a connection
+    Cancel(u32),  // u32 is the ID of the in-flight request to cancel
 
     // --- Onboard backend ---
     GetGameList,
@@ -75,12 +212,60 @@ pub enum RequestBody {
     SetProduction(bool), // Sets prod / dev api url
 
     LaunchGame(String), // String is the game
+    KillGame,           // Stops the running game, gracefully if possible
+    GetRunningGame,
+
+    GetPlayStats(String),      // String is the game ID
+    GetPopularGames(u64, u32), // Seconds to look back, max number of games
+
+    GetStorageUsage,             // Save data usage of every game
+    ExportSaves(Option<String>), // Only this game's save data if given
+    ImportSaves(SaveArchive, ImportMode),
+
+    GetGameLeaderboards(String, u32), // Game ID, max number of scores per board
+    GetAchievements(String),          // String is the game ID
     // ---
 
     // --- Persistence ---
-    Save(String, String, String), // Group, Key, Value
-    Load(String, String),         // Group, Key
+    Save(String, String, String),     // Group, Key, Value
+    Load(String, String),             // Group, Key
+    SaveValue(String, String, Value), // Group, Key, Value
+    LoadValue(String, String),        // Group, Key
+    SaveBytes(String, String, Bytes), // Group, Key, Value
+    LoadBytes(String, String),        // Group, Key
+    Increment(String, String, i64),   // Group, Key, Amount
+    CompareAndSwap(String, String, Option<Value>, Value), // Group, Key, Expected, New
+    Delete(String, String),           // Group, Key
+    ListKeys(String),                 // Group
+    ListGroups,
+    ClearGroup(String), // Group
     Flush,
+
+    // Same as above, but for a single player. The first String is the player's handle from
+    // GetNfcTag, the rest are the same as the shared versions.
+    SavePlayer(String, String, String, String),
+    LoadPlayer(String, String, String),
+    DeletePlayer(String, String, String),
+    ListPlayerKeys(String, String),
+    ListPlayerGroups(String),
+    ClearPlayerGroup(String, String),
+    // ---
+
+    // --- Leaderboards ---
+    SubmitScore(String, i64, Value), // Board, Score, Metadata
+    SubmitPlayerScore(String, String, i64, Value), // Player handle, Board, Score, Metadata
+    GetLeaderboard(String, u32),     // Board, max number of scores
+    // ---
+
+    // --- Achievements ---
+    DefineAchievements(Vec<Achievement>), // Replaces all of the game's achievements
+    UnlockAchievement(String),            // Achievement ID
+    UnlockPlayerAchievement(String, String), // Player handle, Achievement ID
+    // ---
+
+    // --- Events ---
+    Subscribe,   // Start receiving events on this connection
+    Unsubscribe, // Stop receiving events on this connection
     // ---
 
     // --- Gatekeeper ---
@@ -96,6 +281,8 @@ impl RequestBody {
     pub fn variants() -> Vec<Self> {
         vec![
             Self::Ping,
+            Self::Hello(Hello::default()),
+            Self::Cancel(0),
             Self::GetGameList,
             Self::GetGameListFromFs,
             Self::GetGame(String::new()),
@@ -107,9 +294,42 @@ impl RequestBody {
             Self::GetGameListFromTag(String::new()),
             Self::SetProduction(false),
             Self::LaunchGame(String::new()),
+            Self::KillGame,
+            Self::GetRunningGame,
+            Self::GetPlayStats(String::new()),
+            Self::GetPopularGames(0, 0),
+            Self::GetStorageUsage,
+            Self::ExportSaves(None),
+            Self::ImportSaves(SaveArchive::default(), ImportMode::Merge),
+            Self::GetGameLeaderboards(String::new(), 0),
+            Self::GetAchievements(String::new()),
             Self::Save(String::new(), String::new(), String::new()),
             Self::Load(String::new(), String::new()),
+            Self::SaveValue(String::new(), String::new(), Value::Null),
+            Self::LoadValue(String::new(), String::new()),
+            Self::SaveBytes(String::new(), String::new(), Bytes::default()),
+            Self::LoadBytes(String::new(), String::new()),
+            Self::Increment(String::new(), String::new(), 0),
+            Self::CompareAndSwap(String::new(), String::new(), None, Value::Null),
+            Self::Delete(String::new(), String::new()),
+            Self::ListKeys(String::new()),
+            Self::ListGroups,
+            Self::ClearGroup(String::new()),
             Self::Flush,
+            Self::SavePlayer(String::new(), String::new(), String::new(), String::new()),
+            Self::LoadPlayer(String::new(), String::new(), String::new()),
+            Self::DeletePlayer(String::new(), String::new(), String::new()),
+            Self::ListPlayerKeys(String::new(), String::new()),
+            Self::ListPlayerGroups(String::new()),
+            Self::ClearPlayerGroup(String::new(), String::new()),
+            Self::SubmitScore(String::new(), 0, Value::Null),
+            Self::SubmitPlayerScore(String::new(), String::new(), 0, Value::Null),
+            Self::GetLeaderboard(String::new(), 0),
+            Self::DefineAchievements(Vec::new()),
+            Self::UnlockAchievement(String::new()),
+            Self::UnlockPlayerAchievement(String::new(), String::new()),
+            Self::Subscribe,
+            Self::Unsubscribe,
             Self::GetNfcTag(Player::P1),
             Self::GetNfcUser(String::new()),
         ]
@@ -135,11 +355,14 @@ pub struct Response {
 #[serde(tag = "type", content = "data")]
 pub enum ResponseBody {
     Pong,
+    Welcome(Welcome),
 
     Ok,
-    Err(String),
+    Err(ResponseError),
+    Progress(Progress),
 
     GameList(Vec<DevcadeGame>),
+    PartialGameList(PartialGameList),
     Game(DevcadeGame),
 
     TagList(Vec<Tag>),
@@ -148,17 +371,441 @@ pub enum ResponseBody {
     User(User),
 
     Object(String),
+    Value(Value),
+    Bytes(Bytes),
+    Swapped(SwapResult),
+    Keys(Vec<String>),
+    Groups(Vec<String>),
+
+    Rank(Option<u32>),
+    Leaderboard(Leaderboard),
+    Leaderboards(Vec<Leaderboard>),
+
+    Unlocked(bool), // Whether the achievement was newly unlocked
+    Achievements(Vec<AchievementStatus>),
 
     NfcTag(Option<String>),
     NfcUser(Map<String, Value>),
 
-    #[serde(skip)]
-    InternalGame(JoinHandle<ExitStatus>),
+    RunningGame(Option<RunningGame>),
+    PlayStats(PlayStats),
+    PopularGames(Vec<PlayStats>),
+    StorageUsage(Vec<StorageUsage>),
+    SaveArchive(SaveArchive),
+    Imported(ImportSummary),
+
+    Stale(StaleResponse),
+}
+
+/**
+ * Progress of a long running request, sent as zero or more [`ResponseBody::Progress`] responses
+ * before the final response with the same request ID.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "phase")]
+pub enum Progress {
+    /// The game bundle is being downloaded. `total` is missing if the API didn't send a size.
+    Downloading { received: u64, total: Option<u64> },
+    /// The downloaded bundle is being installed by flatpak
+    Installing { status: String, percent: u8 },
+}
+
+/**
+ * The game currently running on the cabinet
+ */
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RunningGame {
+    /// ID of the running game
+    pub game_id: String,
+    /// Process ID of the `flatpak run` process, if it is still known
+    pub pid: Option<u32>,
+    /// When the game was started, in seconds since the unix epoch
+    pub started_at: u64,
+    /// How long the game has been running, in seconds
+    pub runtime_secs: u64,
+}
+
+/**
+ * One play of a game, from launch to exit
+ */
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PlaySession {
+    /// ID of the game that was played
+    pub game_id: String,
+    /// When the game was started, in seconds since the unix epoch
+    pub started_at: u64,
+    /// When the game exited, in seconds since the unix epoch
+    pub ended_at: u64,
+    /// Exit code of the game, missing if it was killed by a signal
+    pub exit_code: Option<i32>,
+    /// Per-game NFC handle of the player, if one identified themselves during the session
+    pub player: Option<String>,
+}
+
+/**
+ * Play time statistics for a single game
+ */
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PlayStats {
+    /// ID of the game
+    pub game_id: String,
+    /// Number of times the game was played
+    pub sessions: u64,
+    /// Total time the game was played, in seconds
+    pub total_secs: u64,
+    /// Number of distinct NFC-identified players
+    pub players: u64,
+    /// When the game was last played, in seconds since the unix epoch
+    pub last_played: Option<u64>,
+}
+
+/**
+ * Raw bytes, sent over the wire as a base64 string
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bytes(pub Vec<u8>);
+
+impl Bytes {
+    /**
+     * Encode the bytes as standard (padded) base64
+     */
+    pub fn to_base64(&self) -> String {
+        base64::engine::general_purpose::STANDARD.encode(&self.0)
+    }
+
+    /**
+     * Decode standard (padded) base64
+     */
+    pub fn from_base64(encoded: &str) -> Result<Self, base64::DecodeError> {
+        base64::engine::general_purpose::STANDARD
+            .decode(encoded)
+            .map(Bytes)
+    }
+}
+
+impl Serialize for Bytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_base64())
+    }
+}
+
+impl<'de> Deserialize<'de> for Bytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        Bytes::from_base64(&encoded).map_err(serde::de::Error::custom)
+    }
+}
+
+/**
+ * Result of a [`RequestBody::CompareAndSwap`]
+ */
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SwapResult {
+    /// Whether the value matched what was expected and was replaced
+    pub swapped: bool,
+    /// The value after the request, missing if the key doesn't exist
+    pub current: Option<Value>,
+}
+
+/**
+ * Value of [`ArchiveManifest::format`] in every save archive
+ */
+pub const SAVE_ARCHIVE_FORMAT: &str = "devcade-save-archive";
+
+/**
+ * Version of the save archive layout written by this version of the backend
+ */
+pub const SAVE_ARCHIVE_VERSION: u32 = 1;
+
+/**
+ * Save data exported from a cabinet, which can be imported into the same or another cabinet
+ */
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SaveArchive {
+    /// What is in the archive
+    pub manifest: ArchiveManifest,
+    /// Every exported group by its full name (starting with the game ID), with its keys and values
+    pub groups: BTreeMap<String, Map<String, Value>>,
+}
+
+/**
+ * Describes the contents of a [`SaveArchive`]
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ArchiveManifest {
+    /// Always [`SAVE_ARCHIVE_FORMAT`]
+    pub format: String,
+    /// Layout version, see [`SAVE_ARCHIVE_VERSION`]
+    pub version: u32,
+    /// When the archive was exported, in seconds since the unix epoch
+    pub created_at: u64,
+    /// The only game in the archive, if a single game was exported
+    pub game_id: Option<String>,
+    /// Number of groups in the archive
+    pub groups: u64,
+    /// Number of keys across all groups in the archive
+    pub keys: u64,
+}
+
+/**
+ * What to do with save data that is already on the cabinet when importing a [`SaveArchive`]
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ImportMode {
+    /// Keep existing data, overwriting only keys that are in the archive
+    Merge,
+    /// Remove existing data of every game in the archive (or all games, if the archive isn't
+    /// limited to one game) before importing. An archive of all games that is empty is refused,
+    /// since it would remove everything.
+    Replace,
+    /// Like `Replace`, but an empty archive of all games is allowed to remove everything
+    ForceReplace,
+}
+
+/**
+ * Result of a [`RequestBody::ImportSaves`]
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImportSummary {
+    /// Number of groups imported
+    pub groups: u64,
+    /// Number of keys imported
+    pub keys: u64,
+    /// Number of existing groups removed because of [`ImportMode::Replace`]
+    pub removed_groups: u64,
+}
+
+/**
+ * Games that were only partly fetched. The games that could be fetched are still usable, the rest
+ * are listed with why they couldn't be fetched.
+ */
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PartialGameList {
+    /// Games that were fetched
+    pub games: Vec<DevcadeGame>,
+    /// Games that couldn't be fetched
+    pub failed: Vec<GameFetchError>,
+}
+
+/**
+ * A response built from cached API data, because the API couldn't be asked for a current copy.
+ * It may be out of date.
+ */
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StaleResponse {
+    /// When the API last confirmed the data, in seconds since the unix epoch
+    pub fetched_at: u64,
+    /// The response built from the cached data
+    pub body: Box<ResponseBody>,
+}
+
+/**
+ * Why a single game couldn't be fetched
+ */
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GameFetchError {
+    /// ID of the game
+    pub game_id: String,
+    /// What went wrong
+    pub error: ResponseError,
+}
+
+/**
+ * A score submitted by a game
+ */
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Score {
+    /// The score itself. Higher scores rank higher.
+    pub score: i64,
+    /// Handle of the player who got the score, if the game knew who was playing
+    pub player: Option<String>,
+    /// Anything else the game wants to show with the score (e.g. initials or a level)
+    pub metadata: Value,
+    /// When the score was submitted, in seconds since the unix epoch
+    pub submitted_at: u64,
+}
+
+/**
+ * The top scores on one of a game's leaderboards, best first
+ */
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Leaderboard {
+    /// Name of the board, as the game submitted it
+    pub board: String,
+    /// Scores on the board, best first. Equal scores are ordered by who got them first.
+    pub scores: Vec<Score>,
+}
+
+/**
+ * An achievement a game can award, as defined by the game
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Achievement {
+    /// Identifies the achievement when the game unlocks it
+    pub id: String,
+    /// Name shown to players
+    pub name: String,
+    /// What a player has to do to unlock it
+    #[serde(default)]
+    pub description: String,
+    /// Whether the name and description should be kept secret until the achievement is unlocked
+    #[serde(default)]
+    pub hidden: bool,
+}
+
+/**
+ * An achievement of a game and who has unlocked it on this cabinet
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AchievementStatus {
+    #[serde(flatten)]
+    pub achievement: Achievement,
+    /// When the achievement was first unlocked, in seconds since the unix epoch. Missing if
+    /// nobody has unlocked it yet.
+    pub unlocked_at: Option<u64>,
+    /// Number of players who unlocked it with their tag presented
+    pub players: u64,
+}
+
+/**
+ * How much save data a single game is storing
+ */
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StorageUsage {
+    /// ID of the game
+    pub game_id: String,
+    /// Number of non-empty save groups
+    pub groups: u64,
+    /// Number of keys across all groups
+    pub keys: u64,
+    /// Size of all keys and values, including changes that haven't been flushed yet
+    pub bytes: u64,
+    /// Size of the game's save files on disk
+    pub disk_bytes: u64,
+}
+
+/**
+ * An unsolicited message pushed by the backend to every client that sent a
+ * [`RequestBody::Subscribe`]. Events are not tied to a request, so they carry an `event` tag
+ * instead of a `request_id`.
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", content = "data")]
+pub enum Event {
+    /// The running game has exited. The exit code is missing if the game was killed by a signal,
+    /// in which case `signal` is set. `killed` is set if the backend stopped the game.
+    GameExited {
+        game_id: String,
+        exit_code: Option<i32>,
+        signal: Option<i32>,
+        runtime_secs: u64,
+        killed: bool,
+    },
+    /// Part of a game has been downloaded. `total` is missing if the size isn't known yet.
+    DownloadProgress {
+        game_id: String,
+        received: u64,
+        total: Option<u64>,
+    },
+    /// The running game got no input for too long and is about to be stopped
+    GameIdle { game_id: String, idle_secs: u64 },
+    /// A gatekeeper tag was presented to a reader. Games get the player's per-game handle with
+    /// [`RequestBody::GetNfcTag`], it is never broadcast.
+    NfcTagPresented { player: Player },
+    /// The backend switched between the production and development API
+    ApiModeChanged { production: bool },
+    /// The API couldn't be reached, so a cached response from `fetched_at` (in seconds since the
+    /// unix epoch) was used instead. Whatever was built from it may be out of date.
+    StaleApiData { url: String, fetched_at: u64 },
+    /// The backend stopped or started sending requests to the API. While it is offline, requests
+    /// are answered from cached or installed data without waiting on the API.
+    ApiConnectivityChanged { online: bool },
+    /// The running game unlocked an achievement, for a single player if `player` is set
+    AchievementUnlocked {
+        game_id: String,
+        achievement: Achievement,
+        player: Option<String>,
+    },
+}
+
+impl Event {
+    /**
+     * Get all enum variants as a vector for debugging.
+     */
+    pub fn variants() -> Vec<Self> {
+        vec![
+            Self::GameExited {
+                game_id: String::new(),
+                exit_code: None,
+                signal: None,
+                runtime_secs: 0,
+                killed: false,
+            },
+            Self::DownloadProgress {
+                game_id: String::new(),
+                received: 0,
+                total: None,
+            },
+            Self::GameIdle {
+                game_id: String::new(),
+                idle_secs: 0,
+            },
+            Self::NfcTagPresented { player: Player::P1 },
+            Self::ApiModeChanged { production: true },
+            Self::StaleApiData {
+                url: String::new(),
+                fetched_at: 0,
+            },
+            Self::ApiConnectivityChanged { online: true },
+            Self::AchievementUnlocked {
+                game_id: String::new(),
+                achievement: Achievement::default(),
+                player: None,
+            },
+        ]
+    }
+}
+
+/**
+ * Anything the backend can write to a socket. Clients can deserialize every line they read into
+ * this to tell responses and events apart.
+ */
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Message {
+    Response(Box<Response>),
+    Event(Event),
+}
+
+impl From<ResponseError> for ResponseBody {
+    fn from(error: ResponseError) -> Self {
+        Self::Err(error)
+    }
+}
+
+impl From<Error> for ResponseError {
+    /// Errors that already are a [`ResponseError`] keep their kind. Anything else is classified as
+    /// well as possible from the error chain.
+    fn from(error: Error) -> Self {
+        match error.downcast::<ResponseError>() {
+            Ok(error) => error,
+            Err(error) => {
+                let kind = if error.chain().any(|cause| cause.is::<std::io::Error>()) {
+                    ErrorKind::Io
+                } else {
+                    ErrorKind::Internal
+                };
+                ResponseError::new(kind, error.to_string())
+            }
+        }
+    }
 }
 
 impl From<Error> for ResponseBody {
     fn from(error: Error) -> Self {
-        Self::Err(error.to_string())
+        Self::Err(error.into())
     }
 }
 
@@ -169,15 +816,36 @@ impl ResponseBody {
     pub fn variants() -> Vec<Self> {
         vec![
             Self::Pong,
+            Self::Welcome(Welcome::default()),
             Self::Ok,
-            Self::Err(String::new()),
+            Self::Err(ResponseError::default()),
+            Self::Progress(Progress::Downloading {
+                received: 0,
+                total: None,
+            }),
             Self::GameList(Vec::new()),
+            Self::PartialGameList(PartialGameList::default()),
             Self::Game(DevcadeGame::default()),
             Self::TagList(Vec::new()),
             Self::Tag(Tag::default()),
             Self::User(User::default()),
             Self::Object(String::from("")),
-            Self::InternalGame(std::thread::spawn(|| std::process::exit(0))),
+            Self::Value(Value::Null),
+            Self::Bytes(Bytes::default()),
+            Self::Swapped(SwapResult::default()),
+            Self::Keys(Vec::new()),
+            Self::Groups(Vec::new()),
+            Self::Rank(None),
+            Self::Leaderboard(Leaderboard::default()),
+            Self::Leaderboards(Vec::new()),
+            Self::Unlocked(false),
+            Self::Achievements(Vec::new()),
+            Self::RunningGame(None),
+            Self::PlayStats(PlayStats::default()),
+            Self::PopularGames(Vec::new()),
+            Self::StorageUsage(Vec::new()),
+            Self::SaveArchive(SaveArchive::default()),
+            Self::Imported(ImportSummary::default()),
             Self::NfcTag(None),
             Self::NfcUser(Map::default()),
         ]
@@ -188,6 +856,10 @@ impl Display for RequestBody {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match &self {
             Self::Ping => write!(f, "Ping"),
+            Self::Hello(Hello {
+                protocol_version, ..
+            }) => write!(f, "Hello with protocol version {protocol_version}"),
+            Self::Cancel(request_id) => write!(f, "Cancel request with id '{request_id}'"),
             Self::GetGameList => write!(f, "Get Game List"),
             Self::GetGameListFromFs => write!(f, "Get Game List From Filesystem"),
             Self::GetGame(game_id) => {
@@ -205,6 +877,20 @@ impl Display for RequestBody {
             Self::LaunchGame(game_id) => {
                 write!(f, "Launch game with id '{game_id}'")
             }
+            Self::KillGame => write!(f, "Kill the running game"),
+            Self::GetRunningGame => write!(f, "Get the running game"),
+            Self::GetPlayStats(game_id) => {
+                write!(f, "Get play stats for game with id '{game_id}'")
+            }
+            Self::GetPopularGames(secs, limit) => {
+                write!(f, "Get top {limit} games of the last {secs}s")
+            }
+            Self::GetStorageUsage => write!(f, "Get save data usage"),
+            Self::ExportSaves(Some(game_id)) => write!(f, "Export save data of game '{game_id}'"),
+            Self::ExportSaves(None) => write!(f, "Export all save data"),
+            Self::ImportSaves(archive, mode) => {
+                write!(f, "Import {} save groups ({mode:?})", archive.groups.len())
+            }
             Self::SetProduction(prod) => {
                 write!(
                     f,
@@ -220,7 +906,68 @@ impl Display for RequestBody {
             Self::GetUser(uid) => write!(f, "Get User with id '{uid}'"),
             Self::Save(group, key, _value) => write!(f, "Save value to {group}/{key}"),
             Self::Load(group, key) => write!(f, "Load value from {group}/{key}"),
+            Self::SaveValue(group, key, _value) => write!(f, "Save JSON value to {group}/{key}"),
+            Self::LoadValue(group, key) => write!(f, "Load JSON value from {group}/{key}"),
+            Self::SaveBytes(group, key, value) => {
+                write!(f, "Save {} bytes to {group}/{key}", value.0.len())
+            }
+            Self::LoadBytes(group, key) => write!(f, "Load bytes from {group}/{key}"),
+            Self::Increment(group, key, amount) => {
+                write!(f, "Increment {group}/{key} by {amount}")
+            }
+            Self::CompareAndSwap(group, key, _expected, _new) => {
+                write!(f, "Compare and swap value at {group}/{key}")
+            }
+            Self::Delete(group, key) => write!(f, "Delete value at {group}/{key}"),
+            Self::ListKeys(group) => write!(f, "List keys in group {group}"),
+            Self::ListGroups => write!(f, "List save data groups"),
+            Self::ClearGroup(group) => write!(f, "Clear all values in group {group}"),
             Self::Flush => write!(f, "Flush cached save data"),
+            Self::SavePlayer(player, group, key, _value) => {
+                write!(f, "Save value to {group}/{key} for player {player}")
+            }
+            Self::LoadPlayer(player, group, key) => {
+                write!(f, "Load value from {group}/{key} for player {player}")
+            }
+            Self::DeletePlayer(player, group, key) => {
+                write!(f, "Delete value at {group}/{key} for player {player}")
+            }
+            Self::ListPlayerKeys(player, group) => {
+                write!(f, "List keys in group {group} for player {player}")
+            }
+            Self::ListPlayerGroups(player) => {
+                write!(f, "List save data groups for player {player}")
+            }
+            Self::ClearPlayerGroup(player, group) => {
+                write!(f, "Clear all values in group {group} for player {player}")
+            }
+            Self::SubmitScore(board, score, _) => {
+                write!(f, "Submit score {score} to leaderboard {board}")
+            }
+            Self::SubmitPlayerScore(player, board, score, _) => {
+                write!(
+                    f,
+                    "Submit score {score} to leaderboard {board} for player {player}"
+                )
+            }
+            Self::GetLeaderboard(board, limit) => {
+                write!(f, "Get top {limit} scores on leaderboard {board}")
+            }
+            Self::GetGameLeaderboards(game_id, limit) => {
+                write!(f, "Get top {limit} scores of game with id '{game_id}'")
+            }
+            Self::GetAchievements(game_id) => {
+                write!(f, "Get achievements of game with id '{game_id}'")
+            }
+            Self::DefineAchievements(achievements) => {
+                write!(f, "Define {} achievements", achievements.len())
+            }
+            Self::UnlockAchievement(id) => write!(f, "Unlock achievement {id}"),
+            Self::UnlockPlayerAchievement(player, id) => {
+                write!(f, "Unlock achievement {id} for player {player}")
+            }
+            Self::Subscribe => write!(f, "Subscribe to events"),
+            Self::Unsubscribe => write!(f, "Unsubscribe from events"),
             Self::GetNfcTag(player) => {
                 write!(f, "Get NFC tags for player '{player}'")
             }
@@ -252,15 +999,49 @@ impl Display for ResponseBody {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match &self {
             Self::Pong => write!(f, "Pong"),
+            Self::Welcome(Welcome {
+                protocol_version, ..
+            }) => write!(f, "Welcome with protocol version {protocol_version}"),
             Self::Ok => write!(f, "Ok"),
-            Self::Err(err) => write!(f, "Err: {err}"),
+            Self::Err(err) => write!(f, "Err ({}): {err}", err.kind),
+            Self::Progress(progress) => write!(f, "Progress: {progress}"),
             Self::GameList(games) => {
                 write!(f, "Got game list with {} games", games.len())
             }
+            Self::PartialGameList(PartialGameList { games, failed }) => write!(
+                f,
+                "Got game list with {} games ({} couldn't be fetched)",
+                games.len(),
+                failed.len()
+            ),
             Self::Game(DevcadeGame { id, .. }) => {
                 write!(f, "Downloaded game with id '{}'", id)
             }
-            Self::InternalGame(_) => write!(f, "Launched game"),
+            Self::RunningGame(Some(RunningGame { game_id, .. })) => {
+                write!(f, "Game with id '{game_id}' is running")
+            }
+            Self::RunningGame(None) => write!(f, "No game is running"),
+            Self::PlayStats(PlayStats {
+                game_id, sessions, ..
+            }) => write!(
+                f,
+                "Got play stats for game with id '{game_id}' ({sessions} sessions)"
+            ),
+            Self::PopularGames(games) => {
+                write!(f, "Got {} popular games", games.len())
+            }
+            Self::SaveArchive(archive) => {
+                write!(f, "Got save archive with {} groups", archive.groups.len())
+            }
+            Self::Imported(ImportSummary { groups, keys, .. }) => {
+                write!(f, "Imported {groups} save groups ({keys} keys)")
+            }
+            Self::Stale(StaleResponse { fetched_at, body }) => {
+                write!(f, "{body} (cached from {fetched_at})")
+            }
+            Self::StorageUsage(games) => {
+                write!(f, "Got save data usage for {} games", games.len())
+            }
             Self::TagList(tags) => {
                 write!(f, "Got tag list with {} tags", tags.len())
             }
@@ -269,6 +1050,28 @@ impl Display for ResponseBody {
             Self::Object(value) => {
                 write!(f, "Got Save data object ({} bytes)", value.bytes().len())
             }
+            Self::Value(value) => write!(f, "Got Save data value '{value}'"),
+            Self::Bytes(value) => write!(f, "Got Save data bytes ({} bytes)", value.0.len()),
+            Self::Swapped(SwapResult { swapped, .. }) => {
+                write!(
+                    f,
+                    "Compare and swap {}",
+                    if *swapped { "succeeded" } else { "failed" }
+                )
+            }
+            Self::Keys(keys) => write!(f, "Got {} save data keys", keys.len()),
+            Self::Groups(groups) => write!(f, "Got {} save data groups", groups.len()),
+            Self::Rank(Some(rank)) => write!(f, "Score ranked #{rank}"),
+            Self::Rank(None) => write!(f, "Score didn't make the leaderboard"),
+            Self::Leaderboard(Leaderboard { board, scores }) => {
+                write!(f, "Got {} scores on leaderboard {board}", scores.len())
+            }
+            Self::Leaderboards(boards) => write!(f, "Got {} leaderboards", boards.len()),
+            Self::Unlocked(true) => write!(f, "Achievement unlocked"),
+            Self::Unlocked(false) => write!(f, "Achievement was already unlocked"),
+            Self::Achievements(achievements) => {
+                write!(f, "Got {} achievements", achievements.len())
+            }
             Self::NfcTag(tag_id) => {
                 write!(f, "Got NFC tag ID '{tag_id:?}'")
             }
@@ -278,3 +1081,90 @@ impl Display for ResponseBody {
         }
     }
 }
+
+impl Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self {
+            Self::GameExited {
+                game_id,
+                exit_code,
+                signal,
+                runtime_secs,
+                killed,
+            } => {
+                write!(
+                    f,
+                    "Game with id '{game_id}' {} after {runtime_secs}s (code {exit_code:?}, signal \
+                     {signal:?})",
+                    if *killed { "was killed" } else { "exited" }
+                )
+            }
+            Self::DownloadProgress {
+                game_id,
+                received,
+                total,
+            } => match total {
+                Some(total) => write!(
+                    f,
+                    "Downloaded {received}/{total} bytes of game with id '{game_id}'"
+                ),
+                None => write!(f, "Downloaded {received} bytes of game with id '{game_id}'"),
+            },
+            Self::GameIdle { game_id, idle_secs } => {
+                write!(f, "Game with id '{game_id}' got no input for {idle_secs}s")
+            }
+            Self::NfcTagPresented { player } => {
+                write!(f, "NFC tag presented to player '{player}'")
+            }
+            Self::StaleApiData { url, fetched_at } => {
+                write!(f, "Served cached response for {url} from {fetched_at}")
+            }
+            Self::ApiConnectivityChanged { online } => write!(
+                f,
+                "API is {}",
+                if *online { "back online" } else { "offline" }
+            ),
+            Self::AchievementUnlocked {
+                game_id,
+                achievement,
+                player,
+            } => match player {
+                Some(player) => write!(
+                    f,
+                    "Player {player} unlocked achievement {} in game with id '{game_id}'",
+                    achievement.id
+                ),
+                None => write!(
+                    f,
+                    "Achievement {} unlocked in game with id '{game_id}'",
+                    achievement.id
+                ),
+            },
+            Self::ApiModeChanged { production } => write!(
+                f,
+                "API set to '{}'",
+                if *production {
+                    "production"
+                } else {
+                    "development"
+                }
+            ),
+        }
+    }
+}
+
+impl Display for Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self {
+            Self::Downloading {
+                received,
+                total: Some(total),
+            } => write!(f, "Downloaded {received}/{total} bytes"),
+            Self::Downloading {
+                received,
+                total: None,
+            } => write!(f, "Downloaded {received} bytes"),
+            Self::Installing { status, percent } => write!(f, "Installing ({percent}%) {status}"),
+        }
+    }
+}